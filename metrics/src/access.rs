@@ -0,0 +1,408 @@
+use crate::error::InvalidCidr;
+use crate::handler::{not_found, Reply};
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD};
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use std::{
+    fmt,
+    net::IpAddr,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// A range of addresses, e.g. `10.0.0.0/8` or `fd00::/8`, a single address is
+/// accepted as a range of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_eq(&net.octets(), &ip.octets(), self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_eq(&net.octets(), &ip.octets(), self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_eq(net: &[u8], ip: &[u8], prefix: u8) -> bool {
+    let prefix = usize::from(prefix);
+    let (bytes, bits) = (prefix / 8, prefix % 8);
+    if net[..bytes] != ip[..bytes] {
+        return false;
+    }
+    bits == 0 || (net[bytes] ^ ip[bytes]) >> (8 - bits) == 0
+}
+
+impl FromStr for Cidr {
+    type Err = InvalidCidr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidCidr(s.to_string());
+        let mut parts = s.splitn(2, '/');
+        let addr: IpAddr = parts.next().unwrap().parse().map_err(|_| invalid())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match parts.next() {
+            Some(prefix) => prefix.parse().map_err(|_| invalid())?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(invalid());
+        }
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+const PBKDF2_ROUNDS: u32 = 600_000;
+
+// password hashes computed at the same time, the requests beyond get 429 so
+// made up credentials can't occupy every executor thread
+const MAX_VERIFYING: usize = 2;
+
+/// Salted PBKDF2-HMAC-SHA256 of `password` in the PHC string format
+/// `$pbkdf2-sha256$i=<rounds>$<salt>$<hash>`, which is what
+/// `MetricServerBuilder::basic_auth` expects, so plain text passwords don't
+/// have to be kept in the configuration. Uses 600000 rounds.
+pub fn hash_password(password: &str) -> String {
+    hash_password_with_rounds(password, PBKDF2_ROUNDS)
+}
+
+/// `hash_password` with `rounds` iterations, fewer rounds make the first
+/// scrape with a password cheaper and a leaked hash cheaper to crack
+pub fn hash_password_with_rounds(password: &str, rounds: u32) -> String {
+    let mut salt = [0u8; 16];
+    getrandom::getrandom(&mut salt).expect("read random salt");
+    let hash = pbkdf2_sha256(password, &salt, rounds, 32);
+    PasswordHash {
+        rounds,
+        salt: salt.to_vec(),
+        hash,
+    }
+    .to_string()
+}
+
+fn pbkdf2_sha256(password: &str, salt: &[u8], rounds: u32, len: usize) -> Vec<u8> {
+    let mut hash = vec![0; len];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, rounds, &mut hash);
+    hash
+}
+
+#[derive(Clone)]
+struct PasswordHash {
+    rounds: u32,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+impl PasswordHash {
+    fn parse(s: &str) -> Option<Self> {
+        let mut parts = s.strip_prefix("$pbkdf2-sha256$i=")?.split('$');
+        let rounds = parts.next()?.parse().ok().filter(|&r| r > 0)?;
+        let salt = STANDARD_NO_PAD.decode(parts.next()?).ok()?;
+        let hash = STANDARD_NO_PAD.decode(parts.next()?).ok()?;
+        if parts.next().is_some() || hash.is_empty() {
+            return None;
+        }
+        Some(PasswordHash { rounds, salt, hash })
+    }
+
+    fn verify(&self, password: &str) -> bool {
+        let hash = pbkdf2_sha256(password, &self.salt, self.rounds, self.hash.len());
+        constant_time_eq(&hash, &self.hash)
+    }
+}
+
+impl fmt::Display for PasswordHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "$pbkdf2-sha256$i={}${}${}",
+            self.rounds,
+            STANDARD_NO_PAD.encode(&self.salt),
+            STANDARD_NO_PAD.encode(&self.hash)
+        )
+    }
+}
+
+// compares all bytes whatever the first difference is
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[derive(Clone)]
+struct Credential {
+    user_hash: Vec<u8>,
+    password_hash: PasswordHash,
+}
+
+/// Who may scrape the metric server, empty allows everyone
+#[derive(Clone, Default)]
+pub(crate) struct AccessControl {
+    allow: Vec<Cidr>,
+    credentials: Vec<Credential>,
+    // SHA-256 of the `user:password` pairs which passed the slow hash, so
+    // every scrape doesn't pay for it again, at most one per credential
+    verified: Arc<Mutex<Vec<Vec<u8>>>>,
+    verifying: Arc<AtomicUsize>,
+    hide: bool,
+}
+
+// a slot of `AccessControl::verifying`, given back when dropped
+struct Verifying<'a>(&'a AtomicUsize);
+
+impl<'a> Verifying<'a> {
+    fn acquire(count: &'a AtomicUsize) -> Option<Self> {
+        count
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < MAX_VERIFYING).then_some(n + 1)
+            })
+            .ok()
+            .map(|_| Verifying(count))
+    }
+}
+
+impl Drop for Verifying<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl AccessControl {
+    pub fn allow(&mut self, cidr: Cidr) {
+        self.allow.push(cidr);
+    }
+
+    /// panics if `password_hash` isn't the format returned by `hash_password`
+    pub fn credential(&mut self, user: &str, password_hash: &str) {
+        let password_hash = PasswordHash::parse(password_hash)
+            .unwrap_or_else(|| panic!("invalid password hash for user {}", user));
+        self.credentials.push(Credential {
+            user_hash: Sha256::digest(user.as_bytes()).to_vec(),
+            password_hash,
+        });
+    }
+
+    pub fn hide(&mut self, hide: bool) {
+        self.hide = hide;
+    }
+
+    /// the reply for a denied request, 403 for a peer outside the allowed
+    /// ranges, 401 for missing or wrong credentials and 429 for credentials
+    /// which aren't verified yet while too many others are, or 404 for all of
+    /// them when the routes are hidden
+    pub fn check(&self, peer: IpAddr, authorization: Option<&str>) -> Result<(), Reply> {
+        if !self.allow.is_empty() && !self.allow.iter().any(|c| c.contains(peer)) {
+            return Err(self.deny(403));
+        }
+        if !self.credentials.is_empty() {
+            self.authorize(authorization)
+                .map_err(|status| self.deny(status))?;
+        }
+        Ok(())
+    }
+
+    fn authorize(&self, authorization: Option<&str>) -> Result<(), u16> {
+        let decoded = authorization
+            .and_then(|value| {
+                let (scheme, token) = value.trim().split_once(' ')?;
+                if scheme.eq_ignore_ascii_case("basic") {
+                    STANDARD.decode(token.trim()).ok()
+                } else {
+                    None
+                }
+            })
+            .and_then(|decoded| String::from_utf8(decoded).ok());
+        let (user, password) = match decoded.as_ref().and_then(|d| d.split_once(':')) {
+            Some(pair) => pair,
+            None => return Err(401),
+        };
+
+        let pair_hash = Sha256::digest(decoded.as_ref().unwrap().as_bytes());
+        let cached =
+            |verified: &Vec<Vec<u8>>| verified.iter().any(|v| constant_time_eq(v, &pair_hash));
+        if cached(&self.verified.lock().unwrap()) {
+            return Ok(());
+        }
+        let _slot = Verifying::acquire(&self.verifying).ok_or(429u16)?;
+        // look at every user so the time doesn't tell which one exists
+        let user_hash = Sha256::digest(user.as_bytes());
+        let found = self.credentials.iter().fold(None, |found, c| {
+            if constant_time_eq(&c.user_hash, &user_hash) {
+                Some(c)
+            } else {
+                found
+            }
+        });
+        let credential = found.unwrap_or(&self.credentials[0]);
+        if !(credential.password_hash.verify(password) & found.is_some()) {
+            return Err(401);
+        }
+        let mut verified = self.verified.lock().unwrap();
+        if !cached(&verified) {
+            verified.push(pair_hash.to_vec());
+        }
+        Ok(())
+    }
+
+    fn deny(&self, status: u16) -> Reply {
+        if self.hide {
+            return not_found();
+        }
+        let mut headers = vec![];
+        if status == 401 {
+            headers.push(("www-authenticate", r#"Basic realm="metrics""#.to_string()));
+        }
+        Reply {
+            status,
+            headers,
+            body: vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hash_password_with_rounds, AccessControl, Cidr, PasswordHash, MAX_VERIFYING};
+    use std::{net::IpAddr, sync::atomic::Ordering, thread};
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_cidr() {
+        let net: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains(ip("10.1.200.3")));
+        assert!(!net.contains(ip("10.2.0.1")));
+        assert!(net.contains(ip("::ffff:10.1.0.1")));
+        let net: Cidr = "192.168.1.128/25".parse().unwrap();
+        assert!(net.contains(ip("192.168.1.200")));
+        assert!(!net.contains(ip("192.168.1.127")));
+        let net: Cidr = "fd00::/8".parse().unwrap();
+        assert!(net.contains(ip("fd12::1")));
+        assert!(!net.contains(ip("fe80::1")));
+        assert!(!net.contains(ip("10.1.0.1")));
+        let net: Cidr = "127.0.0.1".parse().unwrap();
+        assert!(net.contains(ip("127.0.0.1")));
+        assert!(!net.contains(ip("127.0.0.2")));
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(ip("8.8.8.8")));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/x".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn test_password_hash() {
+        // password with the salt `salt` and one round
+        let hash = PasswordHash::parse(
+            "$pbkdf2-sha256$i=1$c2FsdA$Eg+2z/z4syxD5yJSVsT4N6hlSMkszDVICAWYfLcL4Xs",
+        )
+        .unwrap();
+        assert!(hash.verify("password"));
+        assert!(!hash.verify("passwort"));
+
+        let first = hash_password_with_rounds("secret", 1000);
+        let second = hash_password_with_rounds("secret", 1000);
+        assert!(first.starts_with("$pbkdf2-sha256$i=1000$"));
+        assert_ne!(first, second);
+        assert!(PasswordHash::parse(&first).unwrap().verify("secret"));
+
+        assert!(PasswordHash::parse("2bb80d537b1da3e38bd30361aa855686").is_none());
+        assert!(PasswordHash::parse("$pbkdf2-sha256$i=0$c2FsdA$EaR6").is_none());
+        assert!(PasswordHash::parse("$pbkdf2-sha256$i=1$c2FsdA$!!").is_none());
+    }
+
+    #[test]
+    fn test_basic_auth() {
+        let mut access = AccessControl::default();
+        access.credential("prom", &hash_password_with_rounds("secret", 1000));
+        access.credential("other", &hash_password_with_rounds("password", 1000));
+        let peer = ip("10.0.0.1");
+        assert!(access.check(peer, Some("Basic cHJvbTpzZWNyZXQ=")).is_ok());
+        assert!(access
+            .check(peer, Some("basic  b3RoZXI6cGFzc3dvcmQ="))
+            .is_ok());
+        // prom:password
+        let denied = access
+            .check(peer, Some("Basic cHJvbTpwYXNzd29yZA=="))
+            .unwrap_err();
+        assert_eq!(denied.status, 401);
+        assert_eq!(denied.headers[0].0, "www-authenticate");
+        assert!(access.check(peer, None).is_err());
+        assert!(access.check(peer, Some("Bearer cHJvbTpzZWNyZXQ=")).is_err());
+        assert!(access.check(peer, Some("Basic !!!")).is_err());
+        // nobody:secret
+        assert!(access
+            .check(peer, Some("Basic bm9ib2R5OnNlY3JldA=="))
+            .is_err());
+        assert_eq!(access.verified.lock().unwrap().len(), 2);
+        assert!(access.check(peer, Some("Basic cHJvbTpzZWNyZXQ=")).is_ok());
+
+        access.hide(true);
+        let denied = access.check(peer, None).unwrap_err();
+        assert_eq!(denied.status, 404);
+        assert!(denied.headers.is_empty());
+    }
+
+    #[test]
+    fn test_slow_verify_doesnt_block_cached() {
+        let mut access = AccessControl::default();
+        access.credential("prom", &hash_password_with_rounds("secret", 1000));
+        // expensive to check, cheap to make up
+        let slow = PasswordHash {
+            rounds: 50_000,
+            salt: b"salt".to_vec(),
+            hash: vec![0; 32],
+        };
+        access.credential("slow", &slow.to_string());
+        let peer = ip("10.0.0.1");
+        assert!(access.check(peer, Some("Basic cHJvbTpzZWNyZXQ=")).is_ok());
+
+        let checking = access.clone();
+        // slow:wrong
+        let bad = thread::spawn(move || checking.check(peer, Some("Basic c2xvdzp3cm9uZw==")));
+        while access.verifying.load(Ordering::SeqCst) == 0 {
+            thread::yield_now();
+        }
+        assert!(access.check(peer, Some("Basic cHJvbTpzZWNyZXQ=")).is_ok());
+        assert_eq!(access.verifying.load(Ordering::SeqCst), 1);
+        assert_eq!(bad.join().unwrap().unwrap_err().status, 401);
+
+        // beyond the limit only cached credentials get through
+        access.verifying.store(MAX_VERIFYING, Ordering::SeqCst);
+        assert!(access.check(peer, Some("Basic cHJvbTpzZWNyZXQ=")).is_ok());
+        assert_eq!(
+            access
+                .check(peer, Some("Basic c2xvdzp3cm9uZw=="))
+                .unwrap_err()
+                .status,
+            429
+        );
+    }
+
+    #[test]
+    fn test_allow_list() {
+        let mut access = AccessControl::default();
+        assert!(access.check(ip("8.8.8.8"), None).is_ok());
+        access.allow("10.0.0.0/8".parse().unwrap());
+        access.allow("::1".parse().unwrap());
+        assert!(access.check(ip("10.3.2.1"), None).is_ok());
+        assert!(access.check(ip("::1"), None).is_ok());
+        assert_eq!(access.check(ip("8.8.8.8"), None).unwrap_err().status, 403);
+    }
+}
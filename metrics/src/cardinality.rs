@@ -0,0 +1,285 @@
+use prometheus::{
+    core::{Collector, Desc, MetricVec, MetricVecBuilder},
+    proto::MetricFamily,
+    IntCounter, Opts, Result,
+};
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::BuildHasher,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Label value of the series which takes the observations of all label
+/// combinations beyond the limit
+pub const OVERFLOW_LABEL_VALUE: &str = "__overflow__";
+
+// MetricVec with the builder type erased, since the builders can't be named
+// outside of prometheus
+trait LabelledVec<M>: Collector {
+    fn with_label_values(&self, vals: &[&str]) -> M;
+    fn remove_label_values(&self, vals: &[&str]);
+}
+
+impl<T: MetricVecBuilder> LabelledVec<T::M> for MetricVec<T> {
+    fn with_label_values(&self, vals: &[&str]) -> T::M {
+        MetricVec::with_label_values(self, vals)
+    }
+
+    fn remove_label_values(&self, vals: &[&str]) {
+        let _ = MetricVec::remove_label_values(self, vals);
+    }
+}
+
+/// CardinalityLimiter wraps a labelled metric vector and keeps it from
+/// growing past `max_series` label combinations, e.g. when a label is fed
+/// with unbounded values like user ids by mistake.
+///
+/// Once the limit is reached, new label combinations are folded into one
+/// series whose labels are all `__overflow__`, and every folded call is
+/// counted in `metrics_cardinality_overflow_total{family="<name>"}`. Register
+/// the limiter instead of the vector to export both.
+pub struct CardinalityLimiter<M> {
+    vec: Box<dyn LabelledVec<M>>,
+    max_series: usize,
+    overflow: Vec<String>,
+    series: Mutex<Series>,
+    dropped: IntCounter,
+    descs: Vec<Desc>,
+}
+
+// last use of every label combination except the overflow one, keyed by the
+// hash of the values so a lookup doesn't have to allocate them
+#[derive(Default)]
+struct Series {
+    hasher: RandomState,
+    by_hash: HashMap<u64, Vec<(Vec<String>, Instant)>>,
+    len: usize,
+}
+
+impl Series {
+    fn get_mut(&mut self, vals: &[&str]) -> Option<&mut Instant> {
+        let hash = self.hasher.hash_one(vals);
+        self.by_hash
+            .get_mut(&hash)?
+            .iter_mut()
+            .find(|(key, _)| key.iter().eq(vals.iter().copied()))
+            .map(|(_, used)| used)
+    }
+
+    fn insert(&mut self, vals: &[&str], used: Instant) {
+        let hash = self.hasher.hash_one(vals);
+        let key = vals.iter().map(|v| v.to_string()).collect();
+        self.by_hash.entry(hash).or_default().push((key, used));
+        self.len += 1;
+    }
+}
+
+impl<M: Send + Sync + 'static> CardinalityLimiter<M> {
+    /// e.g. a `CardinalityLimiter<IntCounter>` from an `IntCounterVec`
+    pub fn new<T>(vec: MetricVec<T>, max_series: usize) -> Result<Self>
+    where
+        T: MetricVecBuilder<M = M> + 'static,
+    {
+        let desc = vec.desc()[0].clone();
+        let dropped = IntCounter::with_opts(
+            Opts::new(
+                "metrics_cardinality_overflow_total",
+                "Number of label combinations folded into the overflow series.",
+            )
+            .const_label("family", &desc.fq_name),
+        )?;
+        let mut descs = vec![desc.clone()];
+        descs.extend(dropped.desc().into_iter().cloned());
+        Ok(CardinalityLimiter {
+            overflow: vec![OVERFLOW_LABEL_VALUE.to_string(); desc.variable_labels.len()],
+            vec: Box::new(vec),
+            max_series,
+            series: Mutex::new(Series::default()),
+            dropped,
+            descs,
+        })
+    }
+
+    /// the metric of `vals`, or of the overflow series if `vals` is a new label
+    /// combination and the limit is reached
+    ///
+    /// Panics like `MetricVec::with_label_values` if the number of values
+    /// doesn't match the labels.
+    pub fn with_label_values(&self, vals: &[&str]) -> M {
+        // checked before the lock, so a wrong call doesn't poison it
+        assert_eq!(
+            vals.len(),
+            self.overflow.len(),
+            "inconsistent label cardinality"
+        );
+        let now = Instant::now();
+        let mut series = self.series.lock().unwrap();
+        if let Some(used) = series.get_mut(vals) {
+            *used = now;
+        } else if series.len < self.max_series && !self.overflow.iter().eq(vals.iter().copied()) {
+            series.insert(vals, now);
+        } else {
+            self.dropped.inc();
+            let overflow: Vec<&str> = self.overflow.iter().map(String::as_str).collect();
+            return self.vec.with_label_values(&overflow);
+        }
+        self.vec.with_label_values(vals)
+    }
+
+    /// remove the series which weren't used for `idle`, which makes room for
+    /// new label combinations, and return how many were removed
+    pub fn expire_idle(&self, idle: Duration) -> usize {
+        let now = Instant::now();
+        let mut series = self.series.lock().unwrap();
+        let before = series.len;
+        let mut removed = 0;
+        series.by_hash.retain(|_, bucket| {
+            bucket.retain(|(vals, used)| {
+                if now.duration_since(*used) < idle {
+                    return true;
+                }
+                let vals: Vec<&str> = vals.iter().map(String::as_str).collect();
+                self.vec.remove_label_values(&vals);
+                removed += 1;
+                false
+            });
+            !bucket.is_empty()
+        });
+        series.len = before - removed;
+        removed
+    }
+
+    /// label combinations currently tracked, the overflow series excluded
+    pub fn series_count(&self) -> usize {
+        self.series.lock().unwrap().len
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.get() as u64
+    }
+}
+
+impl<M: Send + Sync + 'static> Collector for CardinalityLimiter<M> {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let mut families = self.vec.collect();
+        families.extend(self.dropped.collect());
+        families
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CardinalityLimiter;
+    use prometheus::{IntCounter, IntCounterVec, Opts, Registry};
+    use std::{
+        panic::{self, AssertUnwindSafe},
+        thread,
+        time::Duration,
+    };
+
+    fn limited(max_series: usize) -> CardinalityLimiter<IntCounter> {
+        let vec = IntCounterVec::new(Opts::new("requests", "test"), &["user", "method"]).unwrap();
+        CardinalityLimiter::new(vec, max_series).unwrap()
+    }
+
+    fn values(registry: &Registry) -> Vec<(String, String, i64)> {
+        registry
+            .gather()
+            .iter()
+            .flat_map(|mf| {
+                mf.get_metric().iter().map(move |m| {
+                    let labels: Vec<&str> = m.get_label().iter().map(|l| l.get_value()).collect();
+                    (
+                        mf.get_name().to_string(),
+                        labels.join(","),
+                        m.get_counter().get_value() as i64,
+                    )
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_overflow() {
+        let limiter = limited(2);
+        limiter.with_label_values(&["1", "GET"]).inc();
+        limiter.with_label_values(&["2", "GET"]).inc();
+        limiter.with_label_values(&["3", "GET"]).inc();
+        limiter.with_label_values(&["1", "GET"]).inc();
+        limiter.with_label_values(&["4", "PUT"]).inc_by(2);
+        assert_eq!(limiter.series_count(), 2);
+        assert_eq!(limiter.dropped(), 2);
+
+        let registry = Registry::new();
+        registry.register(Box::new(limiter)).unwrap();
+        assert_eq!(
+            values(&registry),
+            vec![
+                (
+                    "metrics_cardinality_overflow_total".to_string(),
+                    "requests".to_string(),
+                    2
+                ),
+                ("requests".to_string(), "GET,1".to_string(), 2),
+                ("requests".to_string(), "GET,2".to_string(), 1),
+                (
+                    "requests".to_string(),
+                    "__overflow__,__overflow__".to_string(),
+                    3
+                ),
+            ]
+        );
+
+        // limiters of other families export the same counter name
+        registry
+            .register(Box::new(
+                CardinalityLimiter::new(
+                    IntCounterVec::new(Opts::new("errors", "test"), &["user"]).unwrap(),
+                    1,
+                )
+                .unwrap(),
+            ))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_expire_idle() {
+        let limiter = limited(1);
+        limiter.with_label_values(&["1", "GET"]).inc();
+        limiter.with_label_values(&["2", "GET"]).inc();
+        assert_eq!(limiter.dropped(), 1);
+        assert_eq!(limiter.expire_idle(Duration::from_secs(60)), 0);
+
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(limiter.expire_idle(Duration::from_millis(10)), 1);
+        assert_eq!(limiter.series_count(), 0);
+        limiter.with_label_values(&["2", "GET"]).inc();
+        assert_eq!(limiter.dropped(), 1);
+
+        let registry = Registry::new();
+        registry.register(Box::new(limiter)).unwrap();
+        let series: Vec<String> = values(&registry)
+            .into_iter()
+            .filter(|v| v.0 == "requests")
+            .map(|v| v.1)
+            .collect();
+        assert_eq!(series, vec!["GET,2", "__overflow__,__overflow__"]);
+    }
+
+    #[test]
+    fn test_wrong_label_count() {
+        let limiter = limited(2);
+        let wrong = panic::catch_unwind(AssertUnwindSafe(|| limiter.with_label_values(&["1"])));
+        assert!(wrong.is_err());
+        assert_eq!(limiter.series_count(), 0);
+        limiter.with_label_values(&["1", "GET"]).inc();
+        limiter.with_label_values(&["2", "GET"]).inc();
+        assert_eq!(limiter.series_count(), 2);
+        assert_eq!(limiter.dropped(), 0);
+    }
+}
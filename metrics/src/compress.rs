@@ -0,0 +1,95 @@
+use crate::handler::Reply;
+use crate::negotiate;
+use flate2::{
+    write::{GzEncoder, ZlibEncoder},
+    Compression,
+};
+use std::io::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Coding {
+    Gzip,
+    Deflate,
+}
+
+// gzip wins a tie since it is what every scraper sends first
+fn choose_coding(accept_encoding: &[&str]) -> Option<Coding> {
+    let prefs = negotiate::preferences(accept_encoding);
+    let gzip = negotiate::quality(&prefs, "gzip", |_| true).unwrap_or(0.0);
+    let deflate = negotiate::quality(&prefs, "deflate", |_| true).unwrap_or(0.0);
+    if gzip > 0.0 && gzip >= deflate {
+        Some(Coding::Gzip)
+    } else if deflate > 0.0 {
+        Some(Coding::Deflate)
+    } else {
+        None
+    }
+}
+
+fn encode(coding: Coding, body: &[u8]) -> std::io::Result<Vec<u8>> {
+    match coding {
+        Coding::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(body)?;
+            encoder.finish()
+        }
+        Coding::Deflate => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(body)?;
+            encoder.finish()
+        }
+    }
+}
+
+/// Compress `reply` with the coding preferred by the `Accept-Encoding` values
+/// of the request when its body is at least `min_size` bytes long, None leaves
+/// every reply uncompressed.
+pub(crate) fn compress_reply(
+    mut reply: Reply,
+    accept_encoding: &[&str],
+    min_size: Option<usize>,
+) -> Reply {
+    let min_size = match min_size {
+        Some(min_size) => min_size,
+        None => return reply,
+    };
+    reply.headers.push(("vary", "Accept-Encoding".to_string()));
+    if reply.body.len() < min_size {
+        return reply;
+    }
+
+    let coding = match choose_coding(accept_encoding) {
+        Some(coding) => coding,
+        None => return reply,
+    };
+    if let Ok(compressed) = encode(coding, &reply.body) {
+        let value = match coding {
+            Coding::Gzip => "gzip",
+            Coding::Deflate => "deflate",
+        };
+        reply.headers.push(("content-encoding", value.to_string()));
+        reply.body = compressed;
+    }
+    reply
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{choose_coding, Coding};
+
+    fn coding(accept_encoding: &str) -> Option<Coding> {
+        choose_coding(&[accept_encoding])
+    }
+
+    #[test]
+    fn test_choose_coding() {
+        assert_eq!(choose_coding(&[]), None);
+        assert_eq!(coding("identity"), None);
+        assert_eq!(coding("gzip"), Some(Coding::Gzip));
+        assert_eq!(coding("deflate, gzip"), Some(Coding::Gzip));
+        assert_eq!(coding("gzip;q=0.5, deflate"), Some(Coding::Deflate));
+        assert_eq!(coding("gzip;q=0, deflate;q=0"), None);
+        assert_eq!(coding("*"), Some(Coding::Gzip));
+        assert_eq!(coding("*, gzip;q=0"), Some(Coding::Deflate));
+    }
+}
@@ -0,0 +1,87 @@
+use hyper::StatusCode;
+use std::error::Error;
+use std::fmt;
+use std::net::SocketAddr;
+
+// the legacy server reports hyper 0.12 errors, so the sources are boxed
+type BoxError = Box<dyn Error + Send + Sync>;
+
+#[derive(Debug)]
+pub enum ServerError {
+    Bind { addr: SocketAddr, source: BoxError },
+    Serve(BoxError),
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ServerError::Bind { addr, source } => {
+                write!(f, "bind metric server to {} failed: {}", addr, source)
+            }
+            ServerError::Serve(e) => write!(f, "metric server error: {}", e),
+        }
+    }
+}
+
+impl Error for ServerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ServerError::Bind { source, .. } => Some(source.as_ref()),
+            ServerError::Serve(e) => Some(e.as_ref()),
+        }
+    }
+}
+
+/// The string is neither an address nor an `<address>/<prefix length>` range
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidCidr(pub String);
+
+impl fmt::Display for InvalidCidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid address range {}", self.0)
+    }
+}
+
+impl Error for InvalidCidr {}
+
+#[derive(Debug)]
+pub enum PushError {
+    InvalidUrl(String),
+    Encode(prometheus::Error),
+    Http(hyper::Error),
+    Status { status: StatusCode, body: String },
+}
+
+impl PushError {
+    // connection errors and 5xx may be gone on the next attempt
+    pub(crate) fn is_retryable(&self) -> bool {
+        match self {
+            PushError::Http(_) => true,
+            PushError::Status { status, .. } => status.is_server_error(),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for PushError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PushError::InvalidUrl(e) => write!(f, "invalid push url {}", e),
+            PushError::Encode(e) => write!(f, "encode metrics failed: {}", e),
+            PushError::Http(e) => write!(f, "push request failed: {}", e),
+            PushError::Status { status, body } => {
+                write!(f, "push rejected with {}: {}", status, body)
+            }
+        }
+    }
+}
+
+impl Error for PushError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PushError::Encode(e) => Some(e),
+            PushError::Http(e) => Some(e),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,663 @@
+use crate::json_encoder::NamingScheme;
+use crate::source::MetricSources;
+use prometheus::proto::{Metric, MetricType};
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    net::{SocketAddr, TcpStream, UdpSocket},
+    sync::mpsc::{self, RecvTimeoutError, Sender},
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+// keep statsd datagrams below the usual MTU
+const MAX_DATAGRAM: usize = 1432;
+
+// characters which separate the parts of a line, replaced by `_` in names and
+// tags since neither protocol has escaping
+const GRAPHITE_RESERVED: &[char] = &[';', '=', '!', '^', '~'];
+const STATSD_RESERVED: &[char] = &[':', '|', ',', '#', '@'];
+
+/// How labels are sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TagStyle {
+    /// label values are appended to the name, `requests.GET`
+    Dotted,
+    /// labels are sent as tags, `requests:1|c|#method:GET` for DogStatsD and
+    /// `requests;method=GET` for Graphite
+    Tagged,
+}
+
+#[derive(Debug, Clone)]
+pub struct ExporterOptions {
+    prefix: Option<String>,
+    interval: Duration,
+    timeout: Duration,
+    tag_style: TagStyle,
+}
+
+impl Default for ExporterOptions {
+    fn default() -> Self {
+        ExporterOptions {
+            prefix: None,
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(5),
+            tag_style: TagStyle::Dotted,
+        }
+    }
+}
+
+impl ExporterOptions {
+    pub fn new() -> Self {
+        ExporterOptions::default()
+    }
+
+    /// every name is sent as `prefix.name`
+    pub fn prefix<S: Into<String>>(mut self, prefix: S) -> Self {
+        self.prefix = Some(prefix.into());
+        self
+    }
+
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// limit of connecting to Graphite and of every write, 5 seconds by
+    /// default, `start_graphite_exporter` rejects zero
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn tag_style(mut self, tag_style: TagStyle) -> Self {
+        self.tag_style = tag_style;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Counter,
+    Gauge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Sample {
+    name: String,
+    tags: Vec<(String, String)>,
+    value: f64,
+    kind: Kind,
+}
+
+trait Sink: Send {
+    /// send `samples` in order, return how many were sent whole and the error
+    /// which stopped the others
+    fn send(&mut self, samples: &[Sample], style: TagStyle) -> (usize, io::Result<()>);
+}
+
+/// Graphite plaintext protocol over tcp, the connection is opened on the first
+/// flush and again after a failed one
+struct Graphite {
+    addr: SocketAddr,
+    timeout: Duration,
+    stream: Option<TcpStream>,
+}
+
+impl Graphite {
+    fn line(sample: &Sample, style: TagStyle, timestamp: u64) -> String {
+        let mut name = sanitize(&sample.name, GRAPHITE_RESERVED);
+        if style == TagStyle::Tagged {
+            for (k, v) in &sample.tags {
+                name = format!(
+                    "{};{}={}",
+                    name,
+                    sanitize(k, GRAPHITE_RESERVED),
+                    sanitize(v, GRAPHITE_RESERVED)
+                );
+            }
+        }
+        format!("{} {} {}\n", name, sample.value, timestamp)
+    }
+}
+
+impl Sink for Graphite {
+    fn send(&mut self, samples: &[Sample], style: TagStyle) -> (usize, io::Result<()>) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let mut payload = String::new();
+        // end of every sample's line in the payload
+        let mut ends = Vec::with_capacity(samples.len());
+        for sample in samples {
+            payload.push_str(&Graphite::line(sample, style, timestamp));
+            ends.push(payload.len());
+        }
+        if self.stream.is_none() {
+            let stream = TcpStream::connect_timeout(&self.addr, self.timeout)
+                .and_then(|stream| stream.set_write_timeout(Some(self.timeout)).map(|_| stream));
+            match stream {
+                Ok(stream) => self.stream = Some(stream),
+                Err(e) => return (0, Err(e)),
+            }
+        }
+        let (written, res) = write_counted(self.stream.as_mut().unwrap(), payload.as_bytes());
+        if res.is_err() {
+            self.stream = None;
+        }
+        (ends.iter().take_while(|end| **end <= written).count(), res)
+    }
+}
+
+// like `write_all`, but also return how many bytes were written before an error
+fn write_counted<W: Write>(writer: &mut W, buf: &[u8]) -> (usize, io::Result<()>) {
+    let mut written = 0;
+    while written < buf.len() {
+        match writer.write(&buf[written..]) {
+            Ok(0) => return (written, Err(io::ErrorKind::WriteZero.into())),
+            Ok(n) => written += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return (written, Err(e)),
+        }
+    }
+    (written, Ok(()))
+}
+
+/// StatsD over udp, several lines are packed into one datagram
+struct Statsd {
+    addr: SocketAddr,
+    socket: UdpSocket,
+}
+
+impl Statsd {
+    fn lines(sample: &Sample, style: TagStyle) -> Vec<String> {
+        let tags = if style == TagStyle::Tagged && !sample.tags.is_empty() {
+            let tags: Vec<String> = sample
+                .tags
+                .iter()
+                .map(|(k, v)| {
+                    format!(
+                        "{}:{}",
+                        sanitize(k, STATSD_RESERVED),
+                        sanitize(v, STATSD_RESERVED)
+                    )
+                })
+                .collect();
+            format!("|#{}", tags.join(","))
+        } else {
+            String::new()
+        };
+        let name = sanitize(&sample.name, STATSD_RESERVED);
+        match sample.kind {
+            Kind::Counter => vec![format!("{}:{}|c{}", name, sample.value, tags)],
+            // a signed gauge value is read as a change, so it is set to 0 first
+            Kind::Gauge if sample.value < 0.0 => vec![
+                format!("{}:0|g{}", name, tags),
+                format!("{}:{}|g{}", name, sample.value, tags),
+            ],
+            Kind::Gauge => vec![format!("{}:{}|g{}", name, sample.value, tags)],
+        }
+    }
+}
+
+impl Sink for Statsd {
+    fn send(&mut self, samples: &[Sample], style: TagStyle) -> (usize, io::Result<()>) {
+        let mut datagram = String::new();
+        // samples whose lines were all in the datagrams sent so far
+        let mut sent = 0;
+        for (i, sample) in samples.iter().enumerate() {
+            for line in Statsd::lines(sample, style) {
+                if !datagram.is_empty() && datagram.len() + 1 + line.len() > MAX_DATAGRAM {
+                    if let Err(e) = self.socket.send_to(datagram.as_bytes(), self.addr) {
+                        return (sent, Err(e));
+                    }
+                    sent = i;
+                    datagram.clear();
+                }
+                if !datagram.is_empty() {
+                    datagram.push('\n');
+                }
+                datagram.push_str(&line);
+            }
+        }
+        if !datagram.is_empty() {
+            if let Err(e) = self.socket.send_to(datagram.as_bytes(), self.addr) {
+                return (sent, Err(e));
+            }
+        }
+        (samples.len(), Ok(()))
+    }
+}
+
+// replace `reserved` characters, whitespace and control characters with `_`
+fn sanitize(value: &str, reserved: &[char]) -> String {
+    value
+        .chars()
+        .map(|c| {
+            if reserved.contains(&c) || c.is_whitespace() || c.is_control() {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect()
+}
+
+struct Exporter {
+    sources: MetricSources,
+    options: ExporterOptions,
+    sink: Box<dyn Sink>,
+    // last counter values sent, keyed by the sample name and tags
+    last: HashMap<(String, Vec<(String, String)>), f64>,
+}
+
+impl Exporter {
+    fn new(sources: MetricSources, options: ExporterOptions, sink: Box<dyn Sink>) -> Self {
+        Exporter {
+            sources,
+            options,
+            sink,
+            last: HashMap::new(),
+        }
+    }
+
+    fn name(&self, name: &str, m: &Metric, suffix: &str) -> String {
+        let name = match self.options.tag_style {
+            TagStyle::Dotted => NamingScheme::new()
+                .escape_dots(true)
+                .key(name, m)
+                .replace(char::is_whitespace, "_"),
+            TagStyle::Tagged => name.to_string(),
+        };
+        let name = format!("{}{}", name, suffix);
+        match &self.options.prefix {
+            Some(prefix) => format!("{}.{}", prefix, name),
+            None => name,
+        }
+    }
+
+    fn samples(&self) -> Vec<Sample> {
+        let mut samples = vec![];
+        for mf in self.sources.gather() {
+            for m in mf.get_metric() {
+                let tags: Vec<(String, String)> = m
+                    .get_label()
+                    .iter()
+                    .map(|l| (l.get_name().to_string(), l.get_value().to_string()))
+                    .collect();
+                let mut push = |suffix: &str, value: f64, kind: Kind| {
+                    samples.push(Sample {
+                        name: self.name(mf.get_name(), m, suffix),
+                        tags: tags.clone(),
+                        value,
+                        kind,
+                    })
+                };
+                match mf.get_field_type() {
+                    MetricType::COUNTER => push("", m.get_counter().get_value(), Kind::Counter),
+                    MetricType::GAUGE => push("", m.get_gauge().get_value(), Kind::Gauge),
+                    MetricType::UNTYPED => push("", m.get_untyped().get_value(), Kind::Gauge),
+                    MetricType::HISTOGRAM => {
+                        let h = m.get_histogram();
+                        push(".count", h.get_sample_count() as f64, Kind::Counter);
+                        push(".sum", h.get_sample_sum(), Kind::Counter);
+                    }
+                    MetricType::SUMMARY => {
+                        let s = m.get_summary();
+                        for q in s.get_quantile() {
+                            // 0.99 is sent as `name.p99`, 0.999 as `name.p99_9`
+                            let percentile = (q.get_quantile() * 100_000.0).round() / 1000.0;
+                            let suffix = format!(".p{}", percentile.to_string().replace('.', "_"));
+                            push(&suffix, q.get_value(), Kind::Gauge);
+                        }
+                        push(".count", s.get_sample_count() as f64, Kind::Counter);
+                        push(".sum", s.get_sample_sum(), Kind::Counter);
+                    }
+                }
+            }
+        }
+        samples
+    }
+
+    /// Send gauges as they are and counters as the change since they were last
+    /// sent, a counter which went down was reset and is sent whole.
+    fn flush(&mut self) -> io::Result<()> {
+        let mut samples = self.samples();
+        // the key and whole value of every counter sample
+        let mut current = Vec::with_capacity(samples.len());
+        for sample in &mut samples {
+            if sample.kind != Kind::Counter {
+                current.push(None);
+                continue;
+            }
+            let key = (sample.name.clone(), sample.tags.clone());
+            let value = sample.value;
+            if let Some(last) = self.last.get(&key) {
+                if *last <= value {
+                    sample.value = value - last;
+                }
+            }
+            current.push(Some((key, value)));
+        }
+        let (sent, res) = self.sink.send(&samples, self.options.tag_style);
+        if res.is_ok() {
+            // series which are gone are forgotten
+            self.last = current.into_iter().flatten().collect();
+        } else {
+            self.last.extend(current.into_iter().take(sent).flatten());
+        }
+        res
+    }
+}
+
+/// ExporterHandle stops the exporter thread when dropped, after a last flush,
+/// which blocks for at most the connect and write timeouts
+pub struct ExporterHandle {
+    stop_sender: Sender<()>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ExporterHandle {
+    fn spawn<F>(mut exporter: Exporter, on_error: F) -> Self
+    where
+        F: Fn(io::Error) + Send + 'static,
+    {
+        let (stop_sender, stop_receiver) = mpsc::channel();
+        let interval = exporter.options.interval;
+        let thread = thread::spawn(move || loop {
+            let stop = stop_receiver.recv_timeout(interval);
+            // a failed flush is retried with the next one, the deltas are
+            // kept until they are sent
+            if let Err(e) = exporter.flush() {
+                on_error(e);
+            }
+            if stop != Err(RecvTimeoutError::Timeout) {
+                return;
+            }
+        });
+        ExporterHandle {
+            stop_sender,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for ExporterHandle {
+    fn drop(&mut self) {
+        let _ = self.stop_sender.send(());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Send the gathered metrics to a Graphite plaintext listener every interval,
+/// a failed flush is passed to `on_error` and retried with the next one
+pub fn start_graphite_exporter<F>(
+    addr: SocketAddr,
+    sources: MetricSources,
+    options: ExporterOptions,
+    on_error: F,
+) -> io::Result<ExporterHandle>
+where
+    F: Fn(io::Error) + Send + 'static,
+{
+    if options.timeout == Duration::from_secs(0) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "graphite timeout must not be zero",
+        ));
+    }
+    let sink = Graphite {
+        addr,
+        timeout: options.timeout,
+        stream: None,
+    };
+    let exporter = Exporter::new(sources, options, Box::new(sink));
+    Ok(ExporterHandle::spawn(exporter, on_error))
+}
+
+/// Send the gathered metrics to a StatsD or DogStatsD agent every interval,
+/// a failed flush is passed to `on_error` and retried with the next one
+pub fn start_statsd_exporter<F>(
+    addr: SocketAddr,
+    sources: MetricSources,
+    options: ExporterOptions,
+    on_error: F,
+) -> io::Result<ExporterHandle>
+where
+    F: Fn(io::Error) + Send + 'static,
+{
+    let bind: SocketAddr = if addr.is_ipv4() {
+        "0.0.0.0:0".parse().unwrap()
+    } else {
+        "[::]:0".parse().unwrap()
+    };
+    let sink = Statsd {
+        addr,
+        socket: UdpSocket::bind(bind)?,
+    };
+    let exporter = Exporter::new(sources, options, Box::new(sink));
+    Ok(ExporterHandle::spawn(exporter, on_error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        start_graphite_exporter, Exporter, ExporterOptions, Graphite, Kind, Sample, Sink, Statsd,
+        TagStyle,
+    };
+    use crate::source::{MetricSources, SourceOptions};
+    use prometheus::{Gauge, IntCounterVec, Opts, Registry};
+    use std::{
+        io::{self, BufRead, BufReader},
+        net::{TcpListener, UdpSocket},
+        sync::{mpsc, Arc, Mutex},
+        time::Duration,
+    };
+
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<Vec<Sample>>>>);
+
+    impl Sink for Recorder {
+        fn send(&mut self, samples: &[Sample], _: TagStyle) -> (usize, io::Result<()>) {
+            self.0.lock().unwrap().push(samples.to_vec());
+            (samples.len(), Ok(()))
+        }
+    }
+
+    // sends the first `limit` samples of every flush, then fails
+    struct Failing {
+        limit: usize,
+        sent: Recorder,
+    }
+
+    impl Sink for Failing {
+        fn send(&mut self, samples: &[Sample], _: TagStyle) -> (usize, io::Result<()>) {
+            let sent = self.limit.min(samples.len());
+            self.sent.0.lock().unwrap().push(samples[..sent].to_vec());
+            if sent < samples.len() {
+                return (sent, Err(io::ErrorKind::Other.into()));
+            }
+            (sent, Ok(()))
+        }
+    }
+
+    fn registry() -> (Registry, IntCounterVec, Gauge) {
+        let registry = Registry::new();
+        let counter = IntCounterVec::new(Opts::new("requests", "test"), &["method"]).unwrap();
+        let gauge = Gauge::new("temperature", "test").unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        registry.register(Box::new(gauge.clone())).unwrap();
+        (registry, counter, gauge)
+    }
+
+    #[test]
+    fn test_counter_deltas() {
+        let (registry, counter, gauge) = registry();
+        let recorder = Recorder::default();
+        let mut exporter = Exporter::new(
+            MetricSources::new().registry(registry, SourceOptions::new()),
+            ExporterOptions::new().prefix("svc"),
+            Box::new(recorder.clone()),
+        );
+        let values = || {
+            let sent = recorder.0.lock().unwrap();
+            sent.last()
+                .unwrap()
+                .iter()
+                .map(|s| (s.name.clone(), s.value))
+                .collect::<Vec<_>>()
+        };
+
+        counter.with_label_values(&["GET"]).inc_by(5);
+        gauge.set(-3.0);
+        exporter.flush().unwrap();
+        assert_eq!(
+            values(),
+            vec![
+                ("svc.requests.GET".to_string(), 5.0),
+                ("svc.temperature".to_string(), -3.0)
+            ]
+        );
+
+        counter.with_label_values(&["GET"]).inc_by(2);
+        exporter.flush().unwrap();
+        assert_eq!(values()[0], ("svc.requests.GET".to_string(), 2.0));
+        exporter.flush().unwrap();
+        assert_eq!(values()[0], ("svc.requests.GET".to_string(), 0.0));
+    }
+
+    #[test]
+    fn test_partial_send() {
+        let registry = Registry::new();
+        let counter = IntCounterVec::new(Opts::new("requests", "test"), &["method"]).unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        let recorder = Recorder::default();
+        let mut exporter = Exporter::new(
+            MetricSources::new().registry(registry, SourceOptions::new()),
+            ExporterOptions::new(),
+            Box::new(Failing {
+                limit: 1,
+                sent: recorder.clone(),
+            }),
+        );
+        counter.with_label_values(&["GET"]).inc_by(5);
+        counter.with_label_values(&["PUT"]).inc_by(3);
+        assert!(exporter.flush().is_err());
+        counter.with_label_values(&["GET"]).inc_by(1);
+        assert!(exporter.flush().is_err());
+
+        // GET isn't sent again, PUT is sent whole once it goes through
+        let sent = recorder.0.lock().unwrap();
+        assert_eq!(sent[0][0].name, "requests.GET");
+        assert_eq!(sent[0][0].value, 5.0);
+        assert_eq!(sent[1][0].name, "requests.GET");
+        assert_eq!(sent[1][0].value, 1.0);
+        drop(sent);
+        exporter.sink = Box::new(recorder.clone());
+        exporter.flush().unwrap();
+        let sent = recorder.0.lock().unwrap();
+        let values: Vec<f64> = sent[2].iter().map(|s| s.value).collect();
+        assert_eq!(values, vec![0.0, 3.0]);
+    }
+
+    #[test]
+    fn test_statsd_lines() {
+        let (registry, counter, gauge) = registry();
+        counter.with_label_values(&["GET"]).inc_by(5);
+        gauge.set(-3.0);
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let sink = Statsd {
+            addr: receiver.local_addr().unwrap(),
+            socket: UdpSocket::bind("127.0.0.1:0").unwrap(),
+        };
+        let mut exporter = Exporter::new(
+            MetricSources::new().registry(registry, SourceOptions::new()),
+            ExporterOptions::new().tag_style(TagStyle::Tagged),
+            Box::new(sink),
+        );
+        exporter.flush().unwrap();
+
+        let mut buf = [0; 1500];
+        let len = receiver.recv(&mut buf).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&buf[..len]).unwrap(),
+            "requests:5|c|#method:GET\ntemperature:0|g\ntemperature:-3|g"
+        );
+    }
+
+    #[test]
+    fn test_sanitize() {
+        let sample = Sample {
+            name: "requests:total".to_string(),
+            tags: vec![("path".to_string(), "a b;c=d|e,f\ng".to_string())],
+            value: 1.0,
+            kind: Kind::Counter,
+        };
+        assert_eq!(
+            Graphite::line(&sample, TagStyle::Tagged, 10),
+            "requests:total;path=a_b_c_d|e,f_g 1 10\n"
+        );
+        assert_eq!(
+            Statsd::lines(&sample, TagStyle::Tagged),
+            vec!["requests_total:1|c|#path:a_b;c=d_e_f_g"]
+        );
+    }
+
+    #[test]
+    fn test_graphite_exporter() {
+        let (registry, counter, _) = registry();
+        counter.with_label_values(&["GET"]).inc_by(5);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let handle = start_graphite_exporter(
+            listener.local_addr().unwrap(),
+            MetricSources::new().registry(registry, SourceOptions::new()),
+            ExporterOptions::new()
+                .interval(Duration::from_secs(3600))
+                .tag_style(TagStyle::Tagged),
+            |e| panic!("flush failed: {}", e),
+        )
+        .unwrap();
+        // dropping the handle does the final flush
+        drop(handle);
+
+        let (stream, _) = listener.accept().unwrap();
+        let lines = BufReader::new(stream)
+            .lines()
+            .map(|l| l.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("requests;method=GET 5 "));
+        assert!(lines[1].starts_with("temperature 0 "));
+    }
+
+    #[test]
+    fn test_graphite_errors() {
+        let (registry, _, _) = registry();
+        let sources = || MetricSources::new().registry(registry.clone(), SourceOptions::new());
+        // nothing listens on the port once the listener is dropped
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let zero = ExporterOptions::new().timeout(Duration::from_secs(0));
+        let err = start_graphite_exporter(addr, sources(), zero, |_| {})
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let (errors, failed) = mpsc::channel();
+        let options = ExporterOptions::new().interval(Duration::from_millis(10));
+        let _handle = start_graphite_exporter(addr, sources(), options, move |e| {
+            let _ = errors.send(e);
+        })
+        .unwrap();
+        assert!(failed.recv_timeout(Duration::from_secs(5)).is_ok());
+    }
+}
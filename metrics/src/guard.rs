@@ -0,0 +1,108 @@
+use prometheus::{Histogram, IntGauge};
+use std::time::Instant;
+
+/// HistogramTimer observes the seconds elapsed since its creation into a
+/// histogram when it is dropped, e.g. for the latency of a request handler
+/// with several return paths.
+#[must_use = "the timer observes when it is dropped"]
+pub struct HistogramTimer {
+    histogram: Histogram,
+    started: Instant,
+    observed: bool,
+}
+
+impl HistogramTimer {
+    pub fn new(histogram: &Histogram) -> Self {
+        HistogramTimer {
+            histogram: histogram.clone(),
+            started: Instant::now(),
+            observed: false,
+        }
+    }
+
+    /// observe now instead of on drop and return the elapsed seconds
+    pub fn observe_duration(mut self) -> f64 {
+        self.observe()
+    }
+
+    /// drop the timer without observing anything, e.g. for a failed request
+    pub fn discard(mut self) {
+        self.observed = true;
+    }
+
+    fn observe(&mut self) -> f64 {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        self.observed = true;
+        self.histogram.observe(elapsed);
+        elapsed
+    }
+}
+
+impl Drop for HistogramTimer {
+    fn drop(&mut self) {
+        if !self.observed {
+            self.observe();
+        }
+    }
+}
+
+/// IntGaugeGuard increments a gauge when created and decrements it when
+/// dropped, for counting in-flight requests.
+#[must_use = "the gauge is decremented when the guard is dropped"]
+pub struct IntGaugeGuard {
+    gauge: IntGauge,
+}
+
+impl IntGaugeGuard {
+    pub fn new(gauge: &IntGauge) -> Self {
+        gauge.inc();
+        IntGaugeGuard {
+            gauge: gauge.clone(),
+        }
+    }
+}
+
+impl Drop for IntGaugeGuard {
+    fn drop(&mut self) {
+        self.gauge.dec();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HistogramTimer, IntGaugeGuard};
+    use prometheus::{core::Collector, Histogram, HistogramOpts, IntGauge};
+
+    fn count(histogram: &Histogram) -> u64 {
+        histogram.collect()[0].get_metric()[0]
+            .get_histogram()
+            .get_sample_count()
+    }
+
+    #[test]
+    fn test_histogram_timer() {
+        let histogram = Histogram::with_opts(HistogramOpts::new("latency", "test")).unwrap();
+        {
+            let _timer = HistogramTimer::new(&histogram);
+        }
+        assert_eq!(count(&histogram), 1);
+        let elapsed = HistogramTimer::new(&histogram).observe_duration();
+        assert!(elapsed >= 0.0);
+        assert_eq!(count(&histogram), 2);
+        HistogramTimer::new(&histogram).discard();
+        assert_eq!(count(&histogram), 2);
+    }
+
+    #[test]
+    fn test_int_gauge_guard() {
+        let gauge = IntGauge::new("in_flight", "test").unwrap();
+        let first = IntGaugeGuard::new(&gauge);
+        {
+            let _second = IntGaugeGuard::new(&gauge);
+            assert_eq!(gauge.get(), 2);
+        }
+        assert_eq!(gauge.get(), 1);
+        drop(first);
+        assert_eq!(gauge.get(), 0);
+    }
+}
@@ -0,0 +1,248 @@
+use crate::access::AccessControl;
+use crate::compress::compress_reply;
+use crate::health::{CheckKind, HealthChecks};
+use crate::html_encoder::HtmlEncoder;
+use crate::influx_encoder::InfluxEncoder;
+use crate::json_encoder::{
+    JsonEncoder, NamingScheme, StructuredJsonEncoder, STRUCTURED_JSON_FORMAT,
+};
+use crate::negotiate;
+use crate::openmetrics::OpenMetricsEncoder;
+use crate::server::MetricServerBuilder;
+use crate::source::{MetricFilter, MetricSources, SourceOptions};
+use futures::{Future, FutureExt};
+use prometheus::{Encoder, TextEncoder};
+use serde_json::json;
+use std::{net::IpAddr, sync::Arc};
+
+/// The parts of a request the metric routes look at, so the routing is shared
+/// by the hyper 0.14 server and the legacy hyper 0.12 one.
+pub(crate) struct RequestInfo<'a> {
+    pub peer: IpAddr,
+    pub authorization: Option<&'a str>,
+    pub is_get: bool,
+    pub path: &'a str,
+    pub query: &'a str,
+    pub accept: Vec<&'a str>,
+    pub accept_encoding: Vec<&'a str>,
+}
+
+/// A response independent of the http crate version, header names are lower
+/// case
+pub(crate) struct Reply {
+    pub status: u16,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Vec<u8>,
+}
+
+pub(crate) enum Route {
+    Reply(Reply),
+    /// run the checks of this kind and answer with `MetricHandler::health_reply`,
+    /// with the checks only if `detailed`
+    Health {
+        kind: CheckKind,
+        detailed: bool,
+    },
+}
+
+/// The HTML page route and its reload interval in seconds
+pub(crate) struct HtmlRoute {
+    pub path: String,
+    pub refresh: u32,
+}
+
+pub(crate) struct MetricHandler {
+    path_for_prom: String,
+    path_for_http: String,
+    path_for_influx: Option<String>,
+    path_for_html: Option<HtmlRoute>,
+    json_naming: NamingScheme,
+    sources: Arc<MetricSources>,
+    health: Option<HealthChecks>,
+    health_public: bool,
+    compress_min_size: Option<usize>,
+    access: AccessControl,
+}
+
+impl MetricHandler {
+    pub fn new(builder: MetricServerBuilder) -> Self {
+        let sources = match &builder.health {
+            Some(health) => builder
+                .sources
+                .collector(Box::new(health.clone()), SourceOptions::new()),
+            None => builder.sources,
+        };
+        MetricHandler {
+            path_for_prom: builder.path_for_prom,
+            path_for_http: builder.path_for_http,
+            path_for_influx: builder.path_for_influx,
+            path_for_html: builder.path_for_html,
+            json_naming: builder.json_naming,
+            sources: Arc::new(sources),
+            health: builder.health,
+            health_public: builder.health_public,
+            compress_min_size: builder.compress_min_size,
+            access: builder.access,
+        }
+    }
+
+    pub fn route(&self, req: &RequestInfo) -> Route {
+        let path = req.path;
+        let health = self.health.as_ref().and_then(|h| h.kind_for_path(path));
+        let detailed = match self.access.check(req.peer, req.authorization) {
+            Ok(()) => true,
+            Err(_) if health.is_some() && self.health_public => false,
+            Err(denied) => return Route::Reply(denied),
+        };
+        if !req.is_get {
+            return Route::Reply(not_found());
+        }
+
+        let filter = MetricFilter::from_query(req.query);
+        let reply = if path == self.path_for_prom && wants_openmetrics(&req.accept) {
+            let mut encoder = OpenMetricsEncoder::new();
+            if let Some(exemplars) = self.sources.exemplar_store() {
+                encoder = encoder.exemplars(exemplars.clone());
+            }
+            encode_metrics(encoder, &self.sources, &filter)
+        } else if path == self.path_for_prom {
+            encode_metrics(TextEncoder::new(), &self.sources, &filter)
+        } else if path == self.path_for_http && wants_structured(req) {
+            encode_metrics(StructuredJsonEncoder, &self.sources, &filter)
+        } else if path == self.path_for_http {
+            encode_metrics(JsonEncoder::new(self.json_naming), &self.sources, &filter)
+        } else if self.path_for_influx.as_ref().is_some_and(|p| p == path) {
+            encode_metrics(InfluxEncoder::new(), &self.sources, &filter)
+        } else if let Some(html) = self.path_for_html.as_ref().filter(|h| h.path == path) {
+            let encoder = HtmlEncoder::new().refresh(html.refresh);
+            encode_metrics(encoder, &self.sources, &filter)
+        } else if let Some(kind) = health {
+            return Route::Health { kind, detailed };
+        } else {
+            not_found()
+        };
+
+        Route::Reply(compress_reply(
+            reply,
+            &req.accept_encoding,
+            self.compress_min_size,
+        ))
+    }
+
+    /// 200 with the json report if all critical checks of `kind` passed, 503
+    /// otherwise, the report has only the overall status unless `detailed`
+    pub fn health_reply(
+        &self,
+        kind: CheckKind,
+        detailed: bool,
+    ) -> impl Future<Output = Reply> + Send + 'static {
+        let health = self
+            .health
+            .as_ref()
+            .expect("health route without health checks");
+        health.run(kind).map(move |report| match report {
+            Ok(report) => Reply {
+                status: if report.is_healthy() { 200 } else { 503 },
+                headers: vec![("content-type", "application/json".to_string())],
+                body: if detailed {
+                    report.to_json().to_string().into_bytes()
+                } else {
+                    json!({ "status": report.status() })
+                        .to_string()
+                        .into_bytes()
+                },
+            },
+            Err(_) => Reply {
+                status: 503,
+                headers: vec![],
+                body: vec![],
+            },
+        })
+    }
+}
+
+/// `?format=structured` or `Accept: application/vnd.metrics.structured+json`
+/// selects the structured json output on `path_for_http`
+fn wants_structured(req: &RequestInfo) -> bool {
+    let by_query = form_urlencoded::parse(req.query.as_bytes())
+        .any(|(k, v)| k == "format" && v == "structured");
+    let prefs = negotiate::preferences(&req.accept);
+    let by_accept = negotiate::quality(&prefs, STRUCTURED_JSON_FORMAT, |p| !p.value.contains('*'))
+        .is_some_and(|q| q > 0.0);
+    by_query || by_accept
+}
+
+/// OpenMetrics is served only when the client prefers it to the prometheus
+/// text format, so `*/*` and a missing `Accept` keep getting text 0.0.4
+fn wants_openmetrics(accept: &[&str]) -> bool {
+    let prefs = negotiate::preferences(accept);
+    let version_is = |version: &'static str| {
+        move |p: &negotiate::Preference| p.param("version").is_none_or(|v| v == version)
+    };
+    let openmetrics =
+        negotiate::quality(&prefs, "application/openmetrics-text", version_is("1.0.0"));
+    let text = negotiate::quality(&prefs, "text/plain", version_is("0.0.4"));
+    openmetrics.unwrap_or(0.0) > text.unwrap_or(0.0)
+}
+
+fn encode_metrics(encoder: impl Encoder, sources: &MetricSources, filter: &MetricFilter) -> Reply {
+    let metric_families = sources.gather_filtered(filter);
+    let mut buffer = vec![];
+    match encoder.encode(&metric_families, &mut buffer) {
+        Ok(()) => Reply {
+            status: 200,
+            headers: vec![("content-type", encoder.format_type().to_string())],
+            body: buffer,
+        },
+        Err(e) => Reply {
+            status: 500,
+            headers: vec![("content-type", "text/plain; charset=utf-8".to_string())],
+            body: e.to_string().into_bytes(),
+        },
+    }
+}
+
+pub(crate) fn not_found() -> Reply {
+    Reply {
+        status: 404,
+        headers: vec![],
+        body: vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{wants_openmetrics, wants_structured, RequestInfo};
+
+    #[test]
+    fn test_wants_structured() {
+        let req = |query: &'static str, accept: &'static str| RequestInfo {
+            peer: "127.0.0.1".parse().unwrap(),
+            authorization: None,
+            is_get: true,
+            path: "/json",
+            query,
+            accept: vec![accept],
+            accept_encoding: vec![],
+        };
+        assert!(!wants_structured(&req("", "*/*")));
+        assert!(!wants_structured(&req("format=flat", "*/*")));
+        assert!(wants_structured(&req("a=b&format=structured", "*/*")));
+        assert!(wants_structured(&req(
+            "",
+            "text/plain, application/vnd.metrics.structured+json; q=0.9"
+        )));
+    }
+
+    #[test]
+    fn test_wants_openmetrics() {
+        assert!(!wants_openmetrics(&[]));
+        assert!(!wants_openmetrics(&["*/*"]));
+        assert!(wants_openmetrics(&[
+            "application/openmetrics-text;version=1.0.0,text/plain;q=0.5"
+        ]));
+        assert!(!wants_openmetrics(&[
+            "application/openmetrics-text;version=0.0.1"
+        ]));
+    }
+}
@@ -0,0 +1,531 @@
+use futures::{
+    channel::oneshot::{self, Canceled},
+    Future,
+};
+use prometheus::{
+    core::{Collector, Desc},
+    proto::{Gauge, LabelPair, Metric, MetricFamily, MetricType},
+};
+use serde_json::{json, Value};
+use std::{
+    collections::{BTreeMap, HashMap},
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+type CheckFn = dyn Fn() -> Result<(), String> + Send + Sync;
+
+// outcome of one call of a check and how long it took
+type Outcome = (Result<(), String>, Duration);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CheckKind {
+    Liveness,
+    Readiness,
+}
+
+impl CheckKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            CheckKind::Liveness => "liveness",
+            CheckKind::Readiness => "readiness",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckStatus {
+    Ok,
+    Failed,
+    TimedOut,
+}
+
+#[derive(Debug, Clone)]
+pub struct CheckResult {
+    pub name: String,
+    pub critical: bool,
+    pub status: CheckStatus,
+    pub error: Option<String>,
+    pub duration: Duration,
+}
+
+/// Results of one run of the liveness or readiness checks, it is unhealthy if
+/// any critical check didn't pass
+#[derive(Debug, Clone)]
+pub struct HealthReport {
+    pub checks: Vec<CheckResult>,
+}
+
+impl HealthReport {
+    pub fn is_healthy(&self) -> bool {
+        self.checks
+            .iter()
+            .all(|c| !c.critical || c.status == CheckStatus::Ok)
+    }
+
+    /// `fail` if a critical check didn't pass, `degraded` if another one
+    /// didn't, `ok` otherwise
+    pub fn status(&self) -> &'static str {
+        if !self.is_healthy() {
+            "fail"
+        } else if self.checks.iter().any(|c| c.status != CheckStatus::Ok) {
+            "degraded"
+        } else {
+            "ok"
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let status = self.status();
+        let checks: Vec<Value> = self
+            .checks
+            .iter()
+            .map(|c| {
+                json!({
+                    "name": c.name,
+                    "critical": c.critical,
+                    "status": match c.status {
+                        CheckStatus::Ok => "ok",
+                        CheckStatus::Failed => "fail",
+                        CheckStatus::TimedOut => "timeout",
+                    },
+                    "error": c.error,
+                    "duration_ms": c.duration.as_secs_f64() * 1000.0,
+                })
+            })
+            .collect();
+        json!({ "status": status, "checks": checks })
+    }
+}
+
+// a call of a check in progress, the probes which come while it runs wait for
+// it instead of calling the check again
+#[derive(Default)]
+struct Run {
+    outcome: Mutex<Option<Outcome>>,
+    done: Condvar,
+}
+
+impl Run {
+    fn wait_until(&self, deadline: Instant) -> Option<Outcome> {
+        let outcome = self.outcome.lock().unwrap();
+        let wait = deadline.saturating_duration_since(Instant::now());
+        let (outcome, _) = self
+            .done
+            .wait_timeout_while(outcome, wait, |o| o.is_none())
+            .unwrap();
+        outcome.clone()
+    }
+}
+
+#[derive(Clone)]
+struct Check {
+    name: String,
+    kind: CheckKind,
+    timeout: Duration,
+    critical: bool,
+    check: Arc<CheckFn>,
+    running: Arc<Mutex<Option<Arc<Run>>>>,
+}
+
+impl Check {
+    /// the run in progress, or a new one on its own thread
+    fn start(&self) -> Arc<Run> {
+        let mut running = self.running.lock().unwrap();
+        if let Some(run) = running.as_ref() {
+            return run.clone();
+        }
+        let run = Arc::new(Run::default());
+        *running = Some(run.clone());
+
+        let check = self.check.clone();
+        let slot = self.running.clone();
+        let finished = run.clone();
+        thread::spawn(move || {
+            let started = Instant::now();
+            let res = panic::catch_unwind(AssertUnwindSafe(|| check()))
+                .unwrap_or_else(|_| Err("check panicked".to_string()));
+            *slot.lock().unwrap() = None;
+            *finished.outcome.lock().unwrap() = Some((res, started.elapsed()));
+            finished.done.notify_all();
+        });
+        run
+    }
+}
+
+/// HealthChecks holds the named callbacks behind `/healthz` (liveness) and
+/// `/readyz` (readiness).
+///
+/// Every check runs on its own thread, a check which doesn't return within its
+/// timeout is reported as timed out and left running. A check isn't called
+/// again while it runs, probes coming meanwhile wait for the same call, so a
+/// hung check holds one thread whatever the number of probes.
+///
+/// The last result of each check is exported as `health_check_status` (1 ok,
+/// 0 failed) and `health_check_duration_seconds` gauges, a check which hasn't
+/// run yet as 0.
+#[derive(Clone)]
+pub struct HealthChecks {
+    liveness_path: String,
+    readiness_path: String,
+    checks: Vec<Check>,
+    last: Arc<Mutex<BTreeMap<(CheckKind, String), CheckResult>>>,
+    descs: Vec<Desc>,
+}
+
+impl Default for HealthChecks {
+    fn default() -> Self {
+        let labels = vec![
+            "check".to_string(),
+            "kind".to_string(),
+            "critical".to_string(),
+        ];
+        let descs = vec![
+            Desc::new(
+                "health_check_status".to_string(),
+                "Whether the last run of the health check passed.".to_string(),
+                labels.clone(),
+                HashMap::new(),
+            )
+            .unwrap(),
+            Desc::new(
+                "health_check_duration_seconds".to_string(),
+                "Duration of the last run of the health check.".to_string(),
+                labels,
+                HashMap::new(),
+            )
+            .unwrap(),
+        ];
+        HealthChecks {
+            liveness_path: "/healthz".to_string(),
+            readiness_path: "/readyz".to_string(),
+            checks: vec![],
+            last: Arc::new(Mutex::new(BTreeMap::new())),
+            descs,
+        }
+    }
+}
+
+impl HealthChecks {
+    pub fn new() -> Self {
+        HealthChecks::default()
+    }
+
+    pub fn liveness_path<S: Into<String>>(mut self, path: S) -> Self {
+        self.liveness_path = path.into();
+        self
+    }
+
+    pub fn readiness_path<S: Into<String>>(mut self, path: S) -> Self {
+        self.readiness_path = path.into();
+        self
+    }
+
+    /// a check returns an error message when it fails, only failed critical
+    /// checks make the route answer 503
+    pub fn check<S, F>(
+        mut self,
+        kind: CheckKind,
+        name: S,
+        timeout: Duration,
+        critical: bool,
+        check: F,
+    ) -> Self
+    where
+        S: Into<String>,
+        F: Fn() -> Result<(), String> + Send + Sync + 'static,
+    {
+        self.checks.push(Check {
+            name: name.into(),
+            kind,
+            timeout,
+            critical,
+            check: Arc::new(check),
+            running: Arc::default(),
+        });
+        self
+    }
+
+    pub(crate) fn kind_for_path(&self, path: &str) -> Option<CheckKind> {
+        if path == self.liveness_path {
+            Some(CheckKind::Liveness)
+        } else if path == self.readiness_path {
+            Some(CheckKind::Readiness)
+        } else {
+            None
+        }
+    }
+
+    /// run all checks of `kind` concurrently on a background thread, so the
+    /// executor isn't blocked by slow checks
+    pub fn run(
+        &self,
+        kind: CheckKind,
+    ) -> impl Future<Output = Result<HealthReport, Canceled>> + Send + 'static {
+        let (tx, rx) = oneshot::channel();
+        let health = self.clone();
+        thread::spawn(move || {
+            let _ = tx.send(health.run_blocking(kind));
+        });
+        rx
+    }
+
+    pub fn run_blocking(&self, kind: CheckKind) -> HealthReport {
+        let started = Instant::now();
+        let pending: Vec<_> = self
+            .checks
+            .iter()
+            .filter(|c| c.kind == kind)
+            .map(|c| (c, c.start()))
+            .collect();
+
+        let checks: Vec<CheckResult> = pending
+            .into_iter()
+            .map(|(c, run)| {
+                let (status, error, duration) = match run.wait_until(started + c.timeout) {
+                    Some((Ok(()), duration)) => (CheckStatus::Ok, None, duration),
+                    Some((Err(e), duration)) => (CheckStatus::Failed, Some(e), duration),
+                    None => (
+                        CheckStatus::TimedOut,
+                        Some(format!("timed out after {:?}", c.timeout)),
+                        c.timeout,
+                    ),
+                };
+                CheckResult {
+                    name: c.name.clone(),
+                    critical: c.critical,
+                    status,
+                    error,
+                    duration,
+                }
+            })
+            .collect();
+
+        let mut last = self.last.lock().unwrap();
+        for result in &checks {
+            last.insert((kind, result.name.clone()), result.clone());
+        }
+        HealthReport { checks }
+    }
+}
+
+impl Collector for HealthChecks {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        if self.checks.is_empty() {
+            return vec![];
+        }
+        let last = self.last.lock().unwrap();
+        let mut status = MetricFamily::new();
+        status.set_name("health_check_status".to_string());
+        status.set_help(self.descs[0].help.clone());
+        status.set_field_type(MetricType::GAUGE);
+        let mut duration = MetricFamily::new();
+        duration.set_name("health_check_duration_seconds".to_string());
+        duration.set_help(self.descs[1].help.clone());
+        duration.set_field_type(MetricType::GAUGE);
+
+        let checks: BTreeMap<_, _> = self
+            .checks
+            .iter()
+            .map(|c| ((c.kind, c.name.as_str()), c.critical))
+            .collect();
+        for ((kind, name), critical) in checks {
+            let result = last.get(&(kind, name.to_string()));
+            let labels = [
+                ("check", name),
+                ("critical", if critical { "true" } else { "false" }),
+                ("kind", kind.as_str()),
+            ];
+            let ok = match result {
+                Some(result) if result.status == CheckStatus::Ok => 1.0,
+                _ => 0.0,
+            };
+            let seconds = result.map_or(0.0, |r| r.duration.as_secs_f64());
+            status.mut_metric().push(gauge(&labels, ok));
+            duration.mut_metric().push(gauge(&labels, seconds));
+        }
+        vec![status, duration]
+    }
+}
+
+fn gauge(labels: &[(&str, &str)], value: f64) -> Metric {
+    let mut m = Metric::new();
+    for (name, value) in labels {
+        let mut label = LabelPair::new();
+        label.set_name(name.to_string());
+        label.set_value(value.to_string());
+        m.mut_label().push(label);
+    }
+    let mut gauge = Gauge::new();
+    gauge.set_value(value);
+    m.set_gauge(gauge);
+    m
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Check, CheckKind, CheckStatus, HealthChecks};
+    use prometheus::core::Collector;
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+        time::Duration,
+    };
+
+    fn checks() -> HealthChecks {
+        let timeout = Duration::from_millis(100);
+        HealthChecks::new()
+            .check(CheckKind::Liveness, "loop", timeout, true, || Ok(()))
+            .check(CheckKind::Readiness, "db", timeout, true, || Ok(()))
+            .check(CheckKind::Readiness, "cache", timeout, false, || {
+                Err("connection refused".to_string())
+            })
+            .check(CheckKind::Readiness, "slow", timeout, false, || {
+                thread::sleep(Duration::from_secs(1));
+                Ok(())
+            })
+    }
+
+    #[test]
+    fn test_report() {
+        let health = checks();
+        let report = health.run_blocking(CheckKind::Liveness);
+        assert_eq!(report.checks.len(), 1);
+        assert!(report.is_healthy());
+        assert_eq!(report.to_json()["status"], "ok");
+
+        let report = health.run_blocking(CheckKind::Readiness);
+        let statuses = report
+            .checks
+            .iter()
+            .map(|c| (c.name.as_str(), c.status))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            statuses,
+            vec![
+                ("db", CheckStatus::Ok),
+                ("cache", CheckStatus::Failed),
+                ("slow", CheckStatus::TimedOut)
+            ]
+        );
+        assert!(report.is_healthy());
+        let json = report.to_json();
+        assert_eq!(json["status"], "degraded");
+        assert_eq!(json["checks"][1]["error"], "connection refused");
+        assert_eq!(json["checks"][2]["status"], "timeout");
+
+        let report = HealthChecks::new()
+            .check(
+                CheckKind::Readiness,
+                "db",
+                Duration::from_millis(100),
+                true,
+                || Err("down".to_string()),
+            )
+            .run_blocking(CheckKind::Readiness);
+        assert!(!report.is_healthy());
+        assert_eq!(report.to_json()["status"], "fail");
+    }
+
+    #[test]
+    fn test_gauges() {
+        assert!(HealthChecks::new().collect().is_empty());
+        let health = checks();
+        let values = || {
+            let families = health.collect();
+            assert_eq!(families[0].get_name(), "health_check_status");
+            assert_eq!(families[1].get_metric().len(), 4);
+            families[0]
+                .get_metric()
+                .iter()
+                .map(|m| {
+                    (
+                        m.get_label()[0].get_value().to_string(),
+                        m.get_gauge().get_value(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        let expected = |values: &[(&str, f64)]| {
+            values
+                .iter()
+                .map(|(name, value)| (name.to_string(), *value))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            values(),
+            expected(&[("loop", 0.0), ("cache", 0.0), ("db", 0.0), ("slow", 0.0)])
+        );
+
+        health.run_blocking(CheckKind::Readiness);
+        assert_eq!(
+            values(),
+            expected(&[("loop", 0.0), ("cache", 0.0), ("db", 1.0), ("slow", 0.0)])
+        );
+    }
+
+    #[test]
+    fn test_hung_check_not_restarted() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        let health = HealthChecks::new().check(
+            CheckKind::Liveness,
+            "hung",
+            Duration::from_millis(20),
+            true,
+            move || {
+                counted.fetch_add(1, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(200));
+                Ok(())
+            },
+        );
+        for _ in 0..3 {
+            let report = health.run_blocking(CheckKind::Liveness);
+            assert_eq!(report.checks[0].status, CheckStatus::TimedOut);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // a probe coming while the call runs gets its result
+        let report = HealthChecks {
+            checks: health
+                .checks
+                .iter()
+                .map(|c| Check {
+                    timeout: Duration::from_secs(5),
+                    ..c.clone()
+                })
+                .collect(),
+            ..health.clone()
+        }
+        .run_blocking(CheckKind::Liveness);
+        assert_eq!(report.checks[0].status, CheckStatus::Ok);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        health.run_blocking(CheckKind::Liveness);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_panicking_check() {
+        let report = HealthChecks::new()
+            .check(
+                CheckKind::Liveness,
+                "panics",
+                Duration::from_secs(5),
+                true,
+                || panic!("broken"),
+            )
+            .run_blocking(CheckKind::Liveness);
+        assert_eq!(report.checks[0].status, CheckStatus::Failed);
+        assert_eq!(report.checks[0].error.as_deref(), Some("check panicked"));
+    }
+}
@@ -0,0 +1,306 @@
+use prometheus::{
+    proto::{Histogram, Metric, MetricFamily, MetricType},
+    Encoder, Result,
+};
+use std::io::Write;
+
+const HTML_FORMAT: &str = "text/html; charset=utf-8";
+
+// quantiles estimated from histogram buckets
+const QUANTILES: &[f64] = &[0.5, 0.9, 0.99];
+
+const STYLE: &str = "body{font-family:sans-serif;margin:1em 2em}\
+table{border-collapse:collapse;margin-bottom:1em}\
+th,td{border:1px solid #ccc;padding:2px 8px;text-align:left}\
+td.num{text-align:right;font-family:monospace}\
+h2{font-size:1.1em;margin:1.2em 0 0.2em}\
+.type{color:#777;font-weight:normal}\
+.help{color:#444;margin:0 0 0.4em}\
+#filter{width:30em;padding:4px}";
+
+// the filter is kept in the url fragment so it survives the refresh
+const SCRIPT: &str = "var f=document.getElementById('filter');\
+function apply(){var t=f.value.toLowerCase();\
+document.querySelectorAll('.family').forEach(function(e){\
+e.style.display=e.textContent.toLowerCase().indexOf(t)<0?'none':''});\
+history.replaceState(null,'','#'+encodeURIComponent(f.value))}\
+f.value=decodeURIComponent(location.hash.slice(1));f.oninput=apply;apply();";
+
+/// An implementation of an [`Encoder`](::Encoder) that renders a `MetricFamily`
+/// proto message as a self contained HTML page for reading in a browser
+///
+/// Every family is listed with its help text and one table row per metric,
+/// histograms show count, sum and the p50, p90 and p99 estimated from their
+/// buckets. The page reloads itself every `refresh` seconds and has a text box
+/// hiding the families which don't contain the typed text.
+#[derive(Debug)]
+pub struct HtmlEncoder {
+    refresh: u32,
+}
+
+impl Default for HtmlEncoder {
+    fn default() -> Self {
+        HtmlEncoder { refresh: 10 }
+    }
+}
+
+impl HtmlEncoder {
+    pub fn new() -> Self {
+        HtmlEncoder::default()
+    }
+
+    /// seconds between reloads, 0 disables them
+    pub fn refresh(mut self, refresh: u32) -> Self {
+        self.refresh = refresh;
+        self
+    }
+}
+
+impl Encoder for HtmlEncoder {
+    fn encode<W: Write>(&self, metric_familys: &[MetricFamily], writer: &mut W) -> Result<()> {
+        writer.write_all(b"<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">")?;
+        if self.refresh > 0 {
+            write!(
+                writer,
+                "<meta http-equiv=\"refresh\" content=\"{}\">",
+                self.refresh
+            )?;
+        }
+        write!(
+            writer,
+            "<title>Metrics</title><style>{}</style></head>\n<body><h1>Metrics</h1>\n\
+             <input id=\"filter\" type=\"search\" placeholder=\"Filter\" autofocus>\n",
+            STYLE
+        )?;
+
+        for mf in metric_familys {
+            let metric_type = mf.get_field_type();
+            write!(
+                writer,
+                "<div class=\"family\"><h2>{} <span class=\"type\">{}</span></h2>\
+                 <p class=\"help\">{}</p><table>",
+                escape(mf.get_name()),
+                type_name(metric_type),
+                escape(mf.get_help())
+            )?;
+            let headers = headers(metric_type, mf.get_metric());
+            writer.write_all(b"<tr><th>Labels</th>")?;
+            for header in &headers {
+                write!(writer, "<th>{}</th>", escape(header))?;
+            }
+            writer.write_all(b"</tr>")?;
+
+            for m in mf.get_metric() {
+                let labels: Vec<String> = m
+                    .get_label()
+                    .iter()
+                    .map(|l| format!("{}=\"{}\"", l.get_name(), l.get_value()))
+                    .collect();
+                write!(writer, "<tr><td>{}</td>", escape(&labels.join(", ")))?;
+                for value in values(metric_type, m) {
+                    write!(writer, "<td class=\"num\">{}</td>", format_value(value))?;
+                }
+                writer.write_all(b"</tr>")?;
+            }
+            writer.write_all(b"</table></div>\n")?;
+        }
+
+        writeln!(writer, "<script>{}</script></body></html>", SCRIPT)?;
+        Ok(())
+    }
+
+    fn format_type(&self) -> &str {
+        HTML_FORMAT
+    }
+}
+
+fn type_name(metric_type: MetricType) -> &'static str {
+    match metric_type {
+        MetricType::COUNTER => "counter",
+        MetricType::GAUGE => "gauge",
+        MetricType::HISTOGRAM => "histogram",
+        MetricType::SUMMARY => "summary",
+        MetricType::UNTYPED => "untyped",
+    }
+}
+
+fn headers(metric_type: MetricType, metrics: &[Metric]) -> Vec<String> {
+    let mut headers = vec![];
+    match metric_type {
+        MetricType::HISTOGRAM => {
+            headers.extend(vec!["Count".to_string(), "Sum".to_string()]);
+            headers.extend(
+                QUANTILES
+                    .iter()
+                    .map(|q| format!("p{}", format_value(q * 100.0))),
+            );
+        }
+        MetricType::SUMMARY => {
+            headers.extend(vec!["Count".to_string(), "Sum".to_string()]);
+            if let Some(m) = metrics.first() {
+                headers.extend(
+                    m.get_summary()
+                        .get_quantile()
+                        .iter()
+                        .map(|q| format!("p{}", format_value(q.get_quantile() * 100.0))),
+                );
+            }
+        }
+        _ => headers.push("Value".to_string()),
+    }
+    headers
+}
+
+fn values(metric_type: MetricType, m: &Metric) -> Vec<f64> {
+    match metric_type {
+        MetricType::COUNTER => vec![m.get_counter().get_value()],
+        MetricType::GAUGE => vec![m.get_gauge().get_value()],
+        MetricType::UNTYPED => vec![m.get_untyped().get_value()],
+        MetricType::HISTOGRAM => {
+            let h = m.get_histogram();
+            let mut values = vec![h.get_sample_count() as f64, h.get_sample_sum()];
+            values.extend(QUANTILES.iter().map(|q| estimate_quantile(h, *q)));
+            values
+        }
+        MetricType::SUMMARY => {
+            let s = m.get_summary();
+            let mut values = vec![s.get_sample_count() as f64, s.get_sample_sum()];
+            values.extend(s.get_quantile().iter().map(|q| q.get_value()));
+            values
+        }
+    }
+}
+
+/// Estimate quantile `q` by linear interpolation within the bucket containing
+/// it, like `histogram_quantile` in PromQL. Observations above the largest
+/// bound are estimated as that bound, NaN if there are no observations.
+fn estimate_quantile(h: &Histogram, q: f64) -> f64 {
+    let count = h.get_sample_count() as f64;
+    if count == 0.0 {
+        return f64::NAN;
+    }
+    let rank = q * count;
+    let mut lower = 0.0;
+    let mut below = 0.0;
+    for b in h.get_bucket() {
+        let upper = b.get_upper_bound();
+        let cumulative = b.get_cumulative_count() as f64;
+        if cumulative >= rank {
+            if upper.is_infinite() {
+                return lower;
+            }
+            let in_bucket = cumulative - below;
+            if in_bucket == 0.0 {
+                return upper;
+            }
+            return lower + (upper - lower) * (rank - below) / in_bucket;
+        }
+        lower = upper;
+        below = cumulative;
+    }
+    lower
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "-".to_string()
+    } else if value.fract() == 0.0 && value.abs() < 1e15 {
+        format!("{}", value as i64)
+    } else {
+        format!("{:.6}", value)
+            .trim_end_matches('0')
+            .trim_end_matches('.')
+            .to_string()
+    }
+}
+
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{estimate_quantile, format_value, HtmlEncoder};
+    use prometheus::{
+        core::Collector, Encoder, Histogram, HistogramOpts, IntCounterVec, Opts, Registry,
+    };
+
+    #[test]
+    fn test_estimate_quantile() {
+        let histogram =
+            Histogram::with_opts(HistogramOpts::new("latency", "test").buckets(vec![1.0, 2.0]))
+                .unwrap();
+        let estimate = |q| {
+            let mf = histogram.collect();
+            estimate_quantile(mf[0].get_metric()[0].get_histogram(), q)
+        };
+        assert!(estimate(0.5).is_nan());
+
+        for v in &[0.5, 1.5, 1.5, 1.5] {
+            histogram.observe(*v);
+        }
+        // 1 observation in (0, 1], 3 in (1, 2]
+        assert_eq!(estimate(0.25), 1.0);
+        assert_eq!(estimate(0.5), 1.0 + 1.0 / 3.0);
+        assert_eq!(estimate(1.0), 2.0);
+        histogram.observe(10.0);
+        assert_eq!(estimate(0.99), 2.0);
+    }
+
+    #[test]
+    fn test_encode() {
+        let registry = Registry::new();
+        let counter =
+            IntCounterVec::new(Opts::new("requests", "Requests by <path>."), &["path"]).unwrap();
+        counter.with_label_values(&["/a&b"]).inc_by(3);
+        registry.register(Box::new(counter)).unwrap();
+        let histogram =
+            Histogram::with_opts(HistogramOpts::new("latency", "test").buckets(vec![1.0])).unwrap();
+        histogram.observe(0.5);
+        registry.register(Box::new(histogram)).unwrap();
+
+        let mut buffer = vec![];
+        let encoder = HtmlEncoder::new().refresh(5);
+        encoder.encode(&registry.gather(), &mut buffer).unwrap();
+        let html = String::from_utf8(buffer).unwrap();
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains("<meta http-equiv=\"refresh\" content=\"5\">"));
+        assert!(html.contains("<h2>requests <span class=\"type\">counter</span></h2>"));
+        assert!(html.contains("Requests by &lt;path&gt;."));
+        assert!(
+            html.contains("<tr><td>path=&quot;/a&amp;b&quot;</td><td class=\"num\">3</td></tr>")
+        );
+        assert!(html.contains("<th>Count</th><th>Sum</th><th>p50</th><th>p90</th><th>p99</th>"));
+        assert!(html.contains("<td class=\"num\">0.5</td>"));
+        assert!(html.contains("<input id=\"filter\""));
+        assert!(!html.contains("src="));
+        assert!(!html.contains("href="));
+
+        let mut buffer = vec![];
+        HtmlEncoder::new()
+            .refresh(0)
+            .encode(&registry.gather(), &mut buffer)
+            .unwrap();
+        assert!(!String::from_utf8(buffer).unwrap().contains("refresh"));
+    }
+
+    #[test]
+    fn test_format_value() {
+        assert_eq!(format_value(5.0), "5");
+        assert_eq!(format_value(0.25), "0.25");
+        assert_eq!(format_value(1.0 / 3.0), "0.333333");
+        assert_eq!(format_value(f64::NAN), "-");
+        assert_eq!(format_value(f64::INFINITY), "inf");
+    }
+}
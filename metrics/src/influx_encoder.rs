@@ -0,0 +1,256 @@
+use prometheus::{
+    proto::{Metric, MetricFamily, MetricType},
+    Encoder, Result,
+};
+use std::{
+    io::Write,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const INFLUX_FORMAT: &str = "text/plain; charset=utf-8";
+
+/// An implementation of an [`Encoder`](::Encoder) that converts a `MetricFamily` proto message
+/// into InfluxDB line protocol
+///
+/// Every metric is one line with the family name as measurement and the labels
+/// as tags, e.g. "requests{method="GET"} -> 8" becomes `requests,method=GET counter=8`.
+/// Fields follow the Telegraf prometheus input: `counter`, `gauge` or `value`
+/// for untyped metrics, histograms and summaries have `count`, `sum` and one
+/// field per bucket bound or quantile. Fields which aren't finite are left out,
+/// and so are metrics left without fields.
+#[derive(Debug, Default)]
+pub struct InfluxEncoder {
+    timestamps: bool,
+}
+
+impl InfluxEncoder {
+    pub fn new() -> Self {
+        InfluxEncoder::default()
+    }
+
+    /// end every line with the metric timestamp, or the encoding time if the
+    /// metric has none, in nanoseconds
+    pub fn timestamps(mut self, timestamps: bool) -> Self {
+        self.timestamps = timestamps;
+        self
+    }
+}
+
+impl Encoder for InfluxEncoder {
+    fn encode<W: Write>(&self, metric_familys: &[MetricFamily], writer: &mut W) -> Result<()> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0);
+
+        for mf in metric_familys {
+            let measurement = escape(mf.get_name(), &[',', ' ']);
+
+            for m in mf.get_metric() {
+                // line protocol has no NaN or infinity, e.g. the quantiles of
+                // an empty summary, and a line needs at least one field
+                let mut fields = fields(mf.get_field_type(), m);
+                fields.retain(|(_, v)| v.is_finite());
+                if fields.is_empty() {
+                    continue;
+                }
+                write!(writer, "{}", measurement)?;
+                for label in m.get_label() {
+                    // empty tag values are not allowed
+                    if label.get_value().is_empty() {
+                        continue;
+                    }
+                    write!(
+                        writer,
+                        ",{}={}",
+                        escape(label.get_name(), &[',', '=', ' ']),
+                        escape(label.get_value(), &[',', '=', ' '])
+                    )?;
+                }
+                let fields: Vec<String> = fields
+                    .iter()
+                    .map(|(k, v)| format!("{}={}", escape(k, &[',', '=', ' ']), v))
+                    .collect();
+                write!(writer, " {}", fields.join(","))?;
+                if self.timestamps {
+                    let timestamp = if m.has_timestamp_ms() {
+                        i128::from(m.get_timestamp_ms()) * 1_000_000
+                    } else {
+                        now as i128
+                    };
+                    write!(writer, " {}", timestamp)?;
+                }
+                writer.write_all(b"\n")?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn format_type(&self) -> &str {
+        INFLUX_FORMAT
+    }
+}
+
+fn fields(metric_type: MetricType, m: &Metric) -> Vec<(String, f64)> {
+    match metric_type {
+        MetricType::COUNTER => vec![("counter".to_string(), m.get_counter().get_value())],
+        MetricType::GAUGE => vec![("gauge".to_string(), m.get_gauge().get_value())],
+        MetricType::UNTYPED => vec![("value".to_string(), m.get_untyped().get_value())],
+        MetricType::HISTOGRAM => {
+            let h = m.get_histogram();
+            let mut fields = vec![
+                ("count".to_string(), h.get_sample_count() as f64),
+                ("sum".to_string(), h.get_sample_sum()),
+            ];
+            for b in h.get_bucket() {
+                fields.push((
+                    b.get_upper_bound().to_string(),
+                    b.get_cumulative_count() as f64,
+                ));
+            }
+            fields.push(("+Inf".to_string(), h.get_sample_count() as f64));
+            fields
+        }
+        MetricType::SUMMARY => {
+            let s = m.get_summary();
+            let mut fields = vec![
+                ("count".to_string(), s.get_sample_count() as f64),
+                ("sum".to_string(), s.get_sample_sum()),
+            ];
+            for q in s.get_quantile() {
+                fields.push((q.get_quantile().to_string(), q.get_value()));
+            }
+            fields
+        }
+    }
+}
+
+// backslash `special` characters, control characters like `\n` can't be
+// escaped in the line protocol so they are replaced with `_`
+fn escape(value: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c.is_control() {
+            escaped.push('_');
+            continue;
+        }
+        if c == '\\' || special.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::InfluxEncoder;
+    use prometheus::{
+        proto::{Metric, MetricFamily, MetricType, Quantile, Summary},
+        Counter, Encoder, Gauge, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry,
+    };
+
+    fn encode(encoder: &InfluxEncoder, registry: &Registry) -> String {
+        let mut buffer = vec![];
+        encoder.encode(&registry.gather(), &mut buffer).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    #[test]
+    fn test_encode() {
+        let registry = Registry::new();
+        let counter =
+            IntCounterVec::new(Opts::new("requests", "test"), &["method", "path"]).unwrap();
+        counter.with_label_values(&["GET", "/a b,c"]).inc_by(8);
+        counter.with_label_values(&["PUT", ""]).inc();
+        registry.register(Box::new(counter)).unwrap();
+        let histogram = HistogramVec::new(
+            HistogramOpts::new("latency", "test").buckets(vec![0.5, 1.0]),
+            &["method"],
+        )
+        .unwrap();
+        histogram.with_label_values(&["GET"]).observe(0.25);
+        registry.register(Box::new(histogram)).unwrap();
+
+        assert_eq!(
+            encode(&InfluxEncoder::new(), &registry),
+            "latency,method=GET count=1,sum=0.25,0.5=1,1=1,+Inf=1\n\
+             requests,method=GET,path=/a\\ b\\,c counter=8\n\
+             requests,method=PUT counter=1\n"
+        );
+    }
+
+    #[test]
+    fn test_control_characters() {
+        let registry = Registry::new();
+        let counter = IntCounterVec::new(Opts::new("requests", "test"), &["path"]).unwrap();
+        counter.with_label_values(&["/a\nfake,x=1 y=2\r\t"]).inc();
+        registry.register(Box::new(counter)).unwrap();
+
+        let encoded = encode(&InfluxEncoder::new(), &registry);
+        assert_eq!(
+            encoded,
+            "requests,path=/a_fake\\,x\\=1\\ y\\=2__ counter=1\n"
+        );
+        assert_eq!(encoded.lines().count(), 1);
+    }
+
+    #[test]
+    fn test_skip_non_finite() {
+        let registry = Registry::new();
+        let gauge = Gauge::new("temperature", "test").unwrap();
+        gauge.set(f64::NAN);
+        registry.register(Box::new(gauge)).unwrap();
+        let counter = Counter::new("requests", "test").unwrap();
+        counter.inc();
+        registry.register(Box::new(counter)).unwrap();
+        let mut families = registry.gather();
+
+        let mut quantile = Quantile::new();
+        quantile.set_quantile(0.5);
+        quantile.set_value(f64::NAN);
+        let mut summary = Summary::new();
+        summary.set_sample_count(0);
+        summary.set_sample_sum(0.0);
+        summary.mut_quantile().push(quantile);
+        let mut metric = Metric::new();
+        metric.set_summary(summary);
+        let mut rpc = MetricFamily::new();
+        rpc.set_name("rpc".to_string());
+        rpc.set_field_type(MetricType::SUMMARY);
+        rpc.mut_metric().push(metric);
+        families.push(rpc);
+
+        let mut buffer = vec![];
+        InfluxEncoder::new().encode(&families, &mut buffer).unwrap();
+        assert_eq!(
+            String::from_utf8(buffer).unwrap(),
+            "requests counter=1\nrpc count=0,sum=0\n"
+        );
+    }
+
+    #[test]
+    fn test_timestamps() {
+        let registry = Registry::new();
+        let counter = IntCounterVec::new(Opts::new("requests", "test"), &["method"]).unwrap();
+        counter.with_label_values(&["GET"]).inc();
+        registry.register(Box::new(counter)).unwrap();
+
+        let mut families = registry.gather();
+        families[0].mut_metric()[0].set_timestamp_ms(1_565_000_000_000);
+        let mut buffer = vec![];
+        InfluxEncoder::new()
+            .timestamps(true)
+            .encode(&families, &mut buffer)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(buffer).unwrap(),
+            "requests,method=GET counter=1 1565000000000000000\n"
+        );
+
+        let line = encode(&InfluxEncoder::new().timestamps(true), &registry);
+        let timestamp: u128 = line.trim_end().rsplit(' ').next().unwrap().parse().unwrap();
+        assert!(timestamp > 1_565_000_000_000_000_000);
+    }
+}
@@ -1,10 +1,79 @@
 use prometheus::{
-    proto::{LabelPair, Metric, MetricFamily, MetricType},
-    Encoder, Result,
+    proto::{Metric, MetricFamily, MetricType},
+    Encoder, Error, Result,
 };
+use serde_json::{json, Map, Value};
 use std::{collections::HashMap, io::Write};
 
 const JSON_FORMAT: &str = "application/json";
+pub(crate) const STRUCTURED_JSON_FORMAT: &str = "application/vnd.metrics.structured+json";
+
+/// How metric names and labels are flattened into json keys
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NamingScheme {
+    /// `requests.method.GET` instead of `requests.GET`, so labels with the
+    /// same value don't collide
+    pub label_names: bool,
+    /// replace `.` in label values with `_`, so a value can't be mistaken
+    /// for more than one key segment. `a.b` then meets a real `a_b`, which
+    /// is still reported as a collision.
+    pub escape_dots: bool,
+}
+
+impl NamingScheme {
+    pub fn new() -> Self {
+        NamingScheme::default()
+    }
+
+    pub fn label_names(mut self, label_names: bool) -> Self {
+        self.label_names = label_names;
+        self
+    }
+
+    pub fn escape_dots(mut self, escape_dots: bool) -> Self {
+        self.escape_dots = escape_dots;
+        self
+    }
+
+    pub(crate) fn key(&self, name: &str, metric: &Metric) -> String {
+        let mut key = String::from(name);
+        for label in metric.get_label() {
+            if label.get_value().is_empty() {
+                continue;
+            }
+            if self.label_names {
+                key.push('.');
+                key.push_str(label.get_name());
+            }
+            key.push('.');
+            key.push_str(&self.escape(label.get_value()));
+        }
+        key
+    }
+
+    // bucket bounds and quantiles are exported as an extra `.name.value` pair
+    fn key_with(&self, name: &str, metric: &Metric, label: &str, value: f64) -> String {
+        let value = if value == f64::INFINITY {
+            "+Inf".to_string()
+        } else {
+            value.to_string()
+        };
+        format!(
+            "{}.{}.{}",
+            self.key(name, metric),
+            label,
+            self.escape(&value)
+        )
+    }
+
+    fn escape(&self, value: &str) -> String {
+        if self.escape_dots {
+            value.replace('.', "_")
+        } else {
+            value.to_string()
+        }
+    }
+}
 
 /// An implementation of an [`Encoder`](::Encoder) that converts a `MetricFamily` proto message
 /// into `fbagent` json
@@ -12,13 +81,26 @@ const JSON_FORMAT: &str = "application/json";
 /// This implementation converts metric{dimensions,...} -> value to a flat string with a value.
 /// e.g., "requests{method="GET", service="accounts"} -> 8 into
 /// requests.GET.account -> 8
+/// Histograms export `name_bucket.le.X`, `name_count` and `name_sum`, summaries
+/// export `name.quantile.X`, `name_count` and `name_sum`.
+/// Two metrics flattened to the same key are reported as an error, a
+/// `NamingScheme` with `label_names` avoids most of them.
 /// For now, it ignores timestamps (if set on the metric)
 #[derive(Debug, Default)]
-pub struct JsonEncoder;
+pub struct JsonEncoder {
+    naming: NamingScheme,
+}
+
+impl JsonEncoder {
+    pub fn new(naming: NamingScheme) -> Self {
+        JsonEncoder { naming }
+    }
+}
 
 impl Encoder for JsonEncoder {
     fn encode<W: Write>(&self, metric_familys: &[MetricFamily], writer: &mut W) -> Result<()> {
         let mut export_me: HashMap<String, f64> = HashMap::new();
+        let naming = &self.naming;
 
         for mf in metric_familys {
             let name = mf.get_name();
@@ -27,31 +109,71 @@ impl Encoder for JsonEncoder {
             for m in mf.get_metric() {
                 match metric_type {
                     MetricType::COUNTER => {
-                        export_me.insert(
-                            flatten_metric_with_labels(name, m),
+                        export(
+                            &mut export_me,
+                            naming.key(name, m),
                             m.get_counter().get_value(),
-                        );
+                        )?;
                     }
                     MetricType::GAUGE => {
-                        export_me.insert(
-                            flatten_metric_with_labels(name, m),
+                        export(
+                            &mut export_me,
+                            naming.key(name, m),
                             m.get_gauge().get_value(),
-                        );
+                        )?;
+                    }
+                    MetricType::UNTYPED => {
+                        export(
+                            &mut export_me,
+                            naming.key(name, m),
+                            m.get_untyped().get_value(),
+                        )?;
                     }
                     MetricType::HISTOGRAM => {
-                        // write the sum and counts
                         let h = m.get_histogram();
-                        export_me.insert(
-                            flatten_metric_with_labels(&format!("{}_count", name), m),
+                        let bucket = format!("{}_bucket", name);
+                        for b in h.get_bucket() {
+                            export(
+                                &mut export_me,
+                                naming.key_with(&bucket, m, "le", b.get_upper_bound()),
+                                b.get_cumulative_count() as f64,
+                            )?;
+                        }
+                        export(
+                            &mut export_me,
+                            naming.key_with(&bucket, m, "le", f64::INFINITY),
+                            h.get_sample_count() as f64,
+                        )?;
+                        export(
+                            &mut export_me,
+                            naming.key(&format!("{}_count", name), m),
                             h.get_sample_count() as f64,
-                        );
-                        export_me.insert(
-                            flatten_metric_with_labels(&format!("{}_sum", name), m),
+                        )?;
+                        export(
+                            &mut export_me,
+                            naming.key(&format!("{}_sum", name), m),
                             h.get_sample_sum(),
-                        );
+                        )?;
                     }
-                    _ => {
-                        // do nothing; unimplemented
+                    MetricType::SUMMARY => {
+                        let s = m.get_summary();
+                        for q in s.get_quantile() {
+                            export(
+                                &mut export_me,
+                                naming.key_with(name, m, "quantile", q.get_quantile()),
+                                q.get_value(),
+                            )?;
+                        }
+                        export(
+                            &mut export_me,
+                            naming.key(&format!("{}_count", name), m),
+                            s.get_sample_count() as f64,
+                        )?;
+                        export(
+                            &mut export_me,
+                            naming.key(&format!("{}_sum", name), m),
+                            s.get_sample_sum(),
+                        )?;
                     }
                 }
             }
@@ -66,24 +188,297 @@ impl Encoder for JsonEncoder {
     }
 }
 
-fn flatten_metric_with_labels(name: &str, metric: &Metric) -> String {
-    let res = String::from(name);
+/// An implementation of an [`Encoder`](::Encoder) which keeps the labels and
+/// family metadata, it encodes every `MetricFamily` as
+///
+/// {"name": "requests", "help": "...", "type": "counter",
+///  "metrics": [{"labels": {"method": "GET"}, "value": 8, "timestamp": 1565000000000}]}
+///
+/// Histograms have `buckets`, e.g. `[{"le": "0.5", "count": 1}, {"le": "+Inf",
+/// "count": 2}]` sorted by upper bound, plus `count` and `sum` instead of
+/// `value`, summaries have `quantiles`, e.g. `[{"quantile": 0.99, "value": 4}]`
+/// sorted by quantile, plus `count` and `sum`. `timestamp` is in milliseconds
+/// and only present when set on the metric.
+#[derive(Debug, Default)]
+pub struct StructuredJsonEncoder;
 
-    if metric.get_label().is_empty() {
-        res
-    } else {
-        // string-list.join(".")
-        let values: Vec<&str> = metric
-            .get_label()
+impl Encoder for StructuredJsonEncoder {
+    fn encode<W: Write>(&self, metric_familys: &[MetricFamily], writer: &mut W) -> Result<()> {
+        let families: Vec<Value> = metric_familys
             .iter()
-            .map(LabelPair::get_value)
-            .filter(|&x| !x.is_empty())
+            .map(|mf| {
+                let metrics: Vec<Value> = mf
+                    .get_metric()
+                    .iter()
+                    .map(|m| structured_metric(mf.get_field_type(), m))
+                    .collect();
+                json!({
+                    "name": mf.get_name(),
+                    "help": mf.get_help(),
+                    "type": type_name(mf.get_field_type()),
+                    "metrics": metrics,
+                })
+            })
             .collect();
-        let values = values.join(".");
-        if !values.is_empty() {
-            format!("{}.{}", res, values)
-        } else {
-            res
+
+        writer.write_all(serde_json::to_string(&families).unwrap().as_bytes())?;
+        Ok(())
+    }
+
+    fn format_type(&self) -> &str {
+        STRUCTURED_JSON_FORMAT
+    }
+}
+
+fn type_name(metric_type: MetricType) -> &'static str {
+    match metric_type {
+        MetricType::COUNTER => "counter",
+        MetricType::GAUGE => "gauge",
+        MetricType::SUMMARY => "summary",
+        MetricType::UNTYPED => "untyped",
+        MetricType::HISTOGRAM => "histogram",
+    }
+}
+
+// json has no infinity, bounds are strings in the same form as the prometheus
+// text format
+fn float_key(value: f64) -> String {
+    if value == f64::INFINITY {
+        "+Inf".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+fn structured_metric(metric_type: MetricType, m: &Metric) -> Value {
+    let mut obj = Map::new();
+    let labels: Map<String, Value> = m
+        .get_label()
+        .iter()
+        .map(|l| (l.get_name().to_string(), json!(l.get_value())))
+        .collect();
+    obj.insert("labels".to_string(), Value::Object(labels));
+
+    match metric_type {
+        MetricType::COUNTER => {
+            obj.insert("value".to_string(), json!(m.get_counter().get_value()));
+        }
+        MetricType::GAUGE => {
+            obj.insert("value".to_string(), json!(m.get_gauge().get_value()));
         }
+        MetricType::UNTYPED => {
+            obj.insert("value".to_string(), json!(m.get_untyped().get_value()));
+        }
+        MetricType::HISTOGRAM => {
+            let h = m.get_histogram();
+            let mut buckets: Vec<(f64, u64)> = h
+                .get_bucket()
+                .iter()
+                .map(|b| (b.get_upper_bound(), b.get_cumulative_count()))
+                .collect();
+            if buckets.last().is_none_or(|b| b.0 != f64::INFINITY) {
+                buckets.push((f64::INFINITY, h.get_sample_count()));
+            }
+            buckets.sort_by(|a, b| a.0.total_cmp(&b.0));
+            let buckets: Vec<Value> = buckets
+                .into_iter()
+                .map(|(le, count)| json!({"le": float_key(le), "count": count}))
+                .collect();
+            obj.insert("buckets".to_string(), Value::Array(buckets));
+            obj.insert("count".to_string(), json!(h.get_sample_count()));
+            obj.insert("sum".to_string(), json!(h.get_sample_sum()));
+        }
+        MetricType::SUMMARY => {
+            let s = m.get_summary();
+            let mut quantiles: Vec<(f64, f64)> = s
+                .get_quantile()
+                .iter()
+                .map(|q| (q.get_quantile(), q.get_value()))
+                .collect();
+            quantiles.sort_by(|a, b| a.0.total_cmp(&b.0));
+            let quantiles: Vec<Value> = quantiles
+                .into_iter()
+                .map(|(quantile, value)| json!({"quantile": quantile, "value": value}))
+                .collect();
+            obj.insert("quantiles".to_string(), Value::Array(quantiles));
+            obj.insert("count".to_string(), json!(s.get_sample_count()));
+            obj.insert("sum".to_string(), json!(s.get_sample_sum()));
+        }
+    }
+
+    if m.has_timestamp_ms() {
+        obj.insert("timestamp".to_string(), json!(m.get_timestamp_ms()));
+    }
+    Value::Object(obj)
+}
+
+fn export(export_me: &mut HashMap<String, f64>, key: String, value: f64) -> Result<()> {
+    if export_me.contains_key(&key) {
+        return Err(Error::Msg(format!("duplicate json key {}", key)));
+    }
+    export_me.insert(key, value);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{JsonEncoder, NamingScheme, StructuredJsonEncoder};
+    use prometheus::{
+        proto::{Metric, MetricFamily, MetricType, Quantile, Summary},
+        Encoder, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry,
+    };
+    use std::collections::HashMap;
+
+    fn encode(encoder: &JsonEncoder, families: &[MetricFamily]) -> HashMap<String, f64> {
+        let mut buffer = vec![];
+        encoder.encode(families, &mut buffer).unwrap();
+        serde_json::from_slice(&buffer).unwrap()
+    }
+
+    #[test]
+    fn test_histogram_buckets() {
+        let registry = Registry::new();
+        let histogram = HistogramVec::new(
+            HistogramOpts::new("latency", "test").buckets(vec![0.5, 1.0]),
+            &["method"],
+        )
+        .unwrap();
+        histogram.with_label_values(&["GET"]).observe(0.3);
+        histogram.with_label_values(&["GET"]).observe(0.7);
+        registry.register(Box::new(histogram)).unwrap();
+
+        let json = encode(&JsonEncoder::default(), &registry.gather());
+        assert_eq!(json["latency_bucket.GET.le.0.5"], 1.0);
+        assert_eq!(json["latency_bucket.GET.le.1"], 2.0);
+        assert_eq!(json["latency_bucket.GET.le.+Inf"], 2.0);
+        assert_eq!(json["latency_count.GET"], 2.0);
+        assert_eq!(json["latency_sum.GET"], 1.0);
+
+        let naming = NamingScheme::new().label_names(true).escape_dots(true);
+        let json = encode(&JsonEncoder::new(naming), &registry.gather());
+        assert_eq!(json["latency_bucket.method.GET.le.0_5"], 1.0);
+        assert_eq!(json["latency_count.method.GET"], 2.0);
+    }
+
+    #[test]
+    fn test_summary_and_untyped() {
+        let mut summary = Summary::new();
+        summary.set_sample_count(10);
+        summary.set_sample_sum(25.0);
+        let mut quantile = Quantile::new();
+        quantile.set_quantile(0.99);
+        quantile.set_value(4.0);
+        summary.mut_quantile().push(quantile);
+        let mut metric = Metric::new();
+        metric.set_summary(summary);
+        let mut rpc = MetricFamily::new();
+        rpc.set_name("rpc".to_string());
+        rpc.set_field_type(MetricType::SUMMARY);
+        rpc.mut_metric().push(metric);
+
+        let mut metric = Metric::new();
+        metric.mut_untyped().set_value(7.0);
+        let mut temperature = MetricFamily::new();
+        temperature.set_name("temperature".to_string());
+        temperature.set_field_type(MetricType::UNTYPED);
+        temperature.mut_metric().push(metric);
+
+        let json = encode(&JsonEncoder::default(), &[rpc, temperature]);
+        assert_eq!(json["rpc.quantile.0.99"], 4.0);
+        assert_eq!(json["rpc_count"], 10.0);
+        assert_eq!(json["rpc_sum"], 25.0);
+        assert_eq!(json["temperature"], 7.0);
+    }
+
+    #[test]
+    fn test_key_collision() {
+        let registry = Registry::new();
+        let counter =
+            IntCounterVec::new(Opts::new("requests", "test"), &["method", "status"]).unwrap();
+        counter.with_label_values(&["a.b", "c"]).inc();
+        counter.with_label_values(&["a", "b.c"]).inc();
+        registry.register(Box::new(counter)).unwrap();
+
+        let mut buffer = vec![];
+        let err = JsonEncoder::default()
+            .encode(&registry.gather(), &mut buffer)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Error: duplicate json key requests.a.b.c");
+
+        let naming = NamingScheme::new().escape_dots(true);
+        let json = encode(&JsonEncoder::new(naming), &registry.gather());
+        assert_eq!(json["requests.a_b.c"], 1.0);
+        assert_eq!(json["requests.a.b_c"], 1.0);
+
+        let naming = NamingScheme::new().label_names(true);
+        let json = encode(&JsonEncoder::new(naming), &registry.gather());
+        assert_eq!(json["requests.method.a.b.status.c"], 1.0);
+
+        // an escaped dot meets a real underscore
+        let registry = Registry::new();
+        let counter = IntCounterVec::new(Opts::new("requests", "test"), &["path"]).unwrap();
+        counter.with_label_values(&["a.b"]).inc();
+        counter.with_label_values(&["a_b"]).inc();
+        registry.register(Box::new(counter)).unwrap();
+        let naming = NamingScheme::new().escape_dots(true);
+        assert!(JsonEncoder::new(naming)
+            .encode(&registry.gather(), &mut vec![])
+            .is_err());
+    }
+
+    #[test]
+    fn test_structured() {
+        let registry = Registry::new();
+        let counter =
+            IntCounterVec::new(Opts::new("requests", "request count"), &["method"]).unwrap();
+        counter.with_label_values(&["GET"]).inc_by(8);
+        registry.register(Box::new(counter)).unwrap();
+        let histogram = HistogramVec::new(
+            HistogramOpts::new("latency", "request latency").buckets(vec![0.5, 2.5, 10.0]),
+            &["method"],
+        )
+        .unwrap();
+        histogram.with_label_values(&["GET"]).observe(0.3);
+        histogram.with_label_values(&["GET"]).observe(0.7);
+        registry.register(Box::new(histogram)).unwrap();
+
+        let mut families = registry.gather();
+        families[1].mut_metric()[0].set_timestamp_ms(1000);
+        let mut buffer = vec![];
+        StructuredJsonEncoder
+            .encode(&families, &mut buffer)
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&buffer).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!([
+                {
+                    "name": "latency",
+                    "help": "request latency",
+                    "type": "histogram",
+                    "metrics": [{
+                        "labels": {"method": "GET"},
+                        "buckets": [
+                            {"le": "0.5", "count": 1},
+                            {"le": "2.5", "count": 2},
+                            {"le": "10", "count": 2},
+                            {"le": "+Inf", "count": 2},
+                        ],
+                        "count": 2,
+                        "sum": 1.0,
+                    }],
+                },
+                {
+                    "name": "requests",
+                    "help": "request count",
+                    "type": "counter",
+                    "metrics": [{
+                        "labels": {"method": "GET"},
+                        "value": 8.0,
+                        "timestamp": 1000,
+                    }],
+                },
+            ])
+        );
     }
 }
@@ -0,0 +1,159 @@
+//! The metric server on futures 0.1 and hyper 0.12, for services which haven't
+//! moved to std futures yet. It serves the same routes as
+//! [`start_metric_server`](crate::start_metric_server) and will be removed
+//! once the migration is done.
+
+use crate::error::ServerError;
+use crate::handler::{MetricHandler, Reply, RequestInfo, Route};
+use crate::never::Never;
+use crate::server::MetricServerBuilder;
+use futures::{FutureExt, TryFutureExt};
+use futures_01::{future, Future};
+use hyper_012::{
+    header::{HeaderMap, HeaderName, ACCEPT, ACCEPT_ENCODING, AUTHORIZATION},
+    server::conn::AddrStream,
+    service::{make_service_fn, Service},
+    Body, Method, Request, Response, Server,
+};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+struct MetricServer {
+    handler: Arc<MetricHandler>,
+    peer: IpAddr,
+}
+
+impl Service for MetricServer {
+    type ReqBody = Body;
+    type ResBody = Body;
+    type Error = Never;
+    type Future = Box<dyn Future<Item = Response<Body>, Error = Never> + Send>;
+
+    fn call(&mut self, req: Request<Self::ReqBody>) -> Self::Future {
+        let headers = req.headers();
+        let info = RequestInfo {
+            peer: self.peer,
+            authorization: headers.get(AUTHORIZATION).and_then(|v| v.to_str().ok()),
+            is_get: req.method() == Method::GET,
+            path: req.uri().path(),
+            query: req.uri().query().unwrap_or(""),
+            accept: header_values(headers, ACCEPT),
+            accept_encoding: header_values(headers, ACCEPT_ENCODING),
+        };
+        match self.handler.route(&info) {
+            Route::Reply(reply) => Box::new(future::ok(into_response(reply))),
+            Route::Health { kind, detailed } => {
+                let reply = self
+                    .handler
+                    .health_reply(kind, detailed)
+                    .map(Ok::<_, Never>);
+                Box::new(Box::pin(reply).compat().map(into_response))
+            }
+        }
+    }
+}
+
+fn header_values(headers: &HeaderMap, name: HeaderName) -> Vec<&str> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .collect()
+}
+
+fn into_response(reply: Reply) -> Response<Body> {
+    let mut builder = Response::builder();
+    builder.status(reply.status);
+    for (name, value) in reply.headers {
+        builder.header(name, value);
+    }
+    builder.body(Body::from(reply.body)).unwrap()
+}
+
+/// Same as [`start_metric_server`](crate::start_metric_server), but the
+/// returned future and `shutdown` are futures 0.1 ones to be run on tokio 0.1.
+pub fn start_metric_server<S>(
+    builder: MetricServerBuilder,
+    shutdown: S,
+) -> Result<(SocketAddr, impl Future<Item = (), Error = ServerError>), ServerError>
+where
+    S: Future<Item = ()> + Send + 'static,
+{
+    let (addr, handler) = builder.build();
+    let handler = Arc::new(handler);
+    let make_service = make_service_fn(move |conn: &AddrStream| {
+        future::ok::<_, Never>(MetricServer {
+            handler: handler.clone(),
+            peer: conn.remote_addr().ip(),
+        })
+    });
+    let srv = Server::try_bind(&addr)
+        .map_err(|e| ServerError::Bind {
+            addr,
+            source: Box::new(e),
+        })?
+        .serve(make_service);
+    let local_addr = srv.local_addr();
+    let srv = srv
+        .with_graceful_shutdown(shutdown)
+        .map_err(|e| ServerError::Serve(Box::new(e)));
+    Ok((local_addr, srv))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::start_metric_server;
+    use crate::health::{CheckKind, HealthChecks};
+    use crate::server::{tests::test_sources, MetricServerBuilder};
+    use futures_01::{sync::oneshot, Future, Stream};
+    use hyper_012::{Body, Client, Request, StatusCode};
+    use std::time::Duration;
+    use tokio_01::runtime::Runtime;
+
+    fn get(rt: &mut Runtime, url: String) -> (StatusCode, String) {
+        let req = Request::get(url).body(Body::empty()).unwrap();
+        let (status, body) = rt
+            .block_on(Client::new().request(req).and_then(|resp| {
+                let status = resp.status();
+                resp.into_body().concat2().map(move |body| (status, body))
+            }))
+            .unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[test]
+    fn test_serve_and_shutdown() {
+        let mut rt = Runtime::new().unwrap();
+        let (tx, rx) = oneshot::channel::<()>();
+        let health = HealthChecks::new().check(
+            CheckKind::Readiness,
+            "db",
+            Duration::from_millis(100),
+            true,
+            || Err("down".to_string()),
+        );
+        let builder = MetricServerBuilder::new("127.0.0.1:0".parse().unwrap(), test_sources())
+            .health(health)
+            .allow("127.0.0.1".parse().unwrap());
+        let (addr, server) = start_metric_server(builder, rx.map_err(|_| ())).unwrap();
+        let server = oneshot::spawn(server, &rt.executor());
+
+        let (status, body) = get(&mut rt, format!("http://{}/metrics", addr));
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("test_requests 5"));
+        let (status, body) = get(&mut rt, format!("http://{}/json", addr));
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains(r#""test_requests":5.0"#));
+        assert!(body.contains(r#""health_check_status.db.true.readiness":0.0"#));
+        let (status, body) = get(&mut rt, format!("http://{}/readyz", addr));
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body.ends_with(r#""status":"fail"}"#));
+        let (status, _) = get(&mut rt, format!("http://{}/influx", addr));
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        tx.send(()).unwrap();
+        rt.block_on(server).unwrap();
+    }
+}
@@ -1,5 +1,49 @@
+#[macro_use]
+mod macros;
+
+mod access;
+mod cardinality;
+mod compress;
+mod error;
+mod exporter;
+mod guard;
+mod handler;
+mod health;
+mod html_encoder;
+mod influx_encoder;
 mod json_encoder;
+#[cfg(feature = "legacy")]
+pub mod legacy;
+mod negotiate;
+#[cfg(feature = "legacy")]
 mod never;
+mod openmetrics;
+#[cfg(target_os = "linux")]
+mod process;
+mod push;
 mod server;
+mod snapshot;
+mod source;
+
+pub use access::{hash_password, hash_password_with_rounds, Cidr};
+pub use cardinality::{CardinalityLimiter, OVERFLOW_LABEL_VALUE};
+pub use error::{InvalidCidr, PushError, ServerError};
+pub use exporter::{
+    start_graphite_exporter, start_statsd_exporter, ExporterHandle, ExporterOptions, TagStyle,
+};
+pub use guard::{HistogramTimer, IntGaugeGuard};
+pub use health::{CheckKind, CheckResult, CheckStatus, HealthChecks, HealthReport};
+pub use html_encoder::HtmlEncoder;
+pub use influx_encoder::InfluxEncoder;
+pub use json_encoder::{JsonEncoder, NamingScheme, StructuredJsonEncoder};
+pub use openmetrics::{Exemplar, Exemplars, OpenMetricsEncoder, OPENMETRICS_FORMAT};
+#[cfg(target_os = "linux")]
+pub use process::{register_process_collector, ProcessCollector};
+pub use push::PushClient;
+pub use server::{start_metric_server, MetricServerBuilder};
+pub use snapshot::{start_snapshot_dumper, SnapshotFormat, SnapshotHandle, SnapshotOptions};
+pub use source::{MetricFilter, MetricSources, SourceOptions};
 
-pub use server::start_metric_server;
+pub use lazy_static::lazy_static;
+#[doc(hidden)]
+pub use prometheus as __prometheus;
@@ -0,0 +1,175 @@
+//! Macros to create a metric and register it in a given registry in one step,
+//! they evaluate to a `prometheus::Result` of the metric, e.g.
+//!
+//! ```ignore
+//! lazy_static! {
+//!     static ref REGISTRY: Registry = Registry::new();
+//!     static ref REQUESTS: IntCounterVec = register_int_counter_vec_in!(
+//!         REGISTRY,
+//!         "requests_total",
+//!         "Number of requests.",
+//!         &["method"]
+//!     )
+//!     .unwrap();
+//! }
+//! ```
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __register_in {
+    ($registry:expr, $metric:expr) => {{
+        let metric = $metric;
+        $registry.register(Box::new(metric.clone())).map(|_| metric)
+    }};
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __register_opts_in {
+    ($registry:expr, $type:ident, $name:expr, $help:expr) => {
+        $crate::__prometheus::$type::with_opts($crate::__prometheus::Opts::new($name, $help))
+            .and_then(|metric| $crate::__register_in!($registry, metric))
+    };
+    ($registry:expr, $type:ident, $name:expr, $help:expr, $labels:expr) => {
+        $crate::__prometheus::$type::new($crate::__prometheus::Opts::new($name, $help), $labels)
+            .and_then(|metric| $crate::__register_in!($registry, metric))
+    };
+}
+
+#[macro_export]
+macro_rules! register_counter_in {
+    ($registry:expr, $name:expr, $help:expr $(,)?) => {
+        $crate::__register_opts_in!($registry, Counter, $name, $help)
+    };
+}
+
+#[macro_export]
+macro_rules! register_counter_vec_in {
+    ($registry:expr, $name:expr, $help:expr, $labels:expr $(,)?) => {
+        $crate::__register_opts_in!($registry, CounterVec, $name, $help, $labels)
+    };
+}
+
+#[macro_export]
+macro_rules! register_int_counter_in {
+    ($registry:expr, $name:expr, $help:expr $(,)?) => {
+        $crate::__register_opts_in!($registry, IntCounter, $name, $help)
+    };
+}
+
+#[macro_export]
+macro_rules! register_int_counter_vec_in {
+    ($registry:expr, $name:expr, $help:expr, $labels:expr $(,)?) => {
+        $crate::__register_opts_in!($registry, IntCounterVec, $name, $help, $labels)
+    };
+}
+
+#[macro_export]
+macro_rules! register_gauge_in {
+    ($registry:expr, $name:expr, $help:expr $(,)?) => {
+        $crate::__register_opts_in!($registry, Gauge, $name, $help)
+    };
+}
+
+#[macro_export]
+macro_rules! register_gauge_vec_in {
+    ($registry:expr, $name:expr, $help:expr, $labels:expr $(,)?) => {
+        $crate::__register_opts_in!($registry, GaugeVec, $name, $help, $labels)
+    };
+}
+
+#[macro_export]
+macro_rules! register_int_gauge_in {
+    ($registry:expr, $name:expr, $help:expr $(,)?) => {
+        $crate::__register_opts_in!($registry, IntGauge, $name, $help)
+    };
+}
+
+#[macro_export]
+macro_rules! register_int_gauge_vec_in {
+    ($registry:expr, $name:expr, $help:expr, $labels:expr $(,)?) => {
+        $crate::__register_opts_in!($registry, IntGaugeVec, $name, $help, $labels)
+    };
+}
+
+/// the buckets default to `prometheus::DEFAULT_BUCKETS`
+#[macro_export]
+macro_rules! register_histogram_in {
+    ($registry:expr, $name:expr, $help:expr $(,)?) => {
+        $crate::register_histogram_in!(
+            $registry,
+            $name,
+            $help,
+            $crate::__prometheus::DEFAULT_BUCKETS.to_vec()
+        )
+    };
+    ($registry:expr, $name:expr, $help:expr, $buckets:expr $(,)?) => {
+        $crate::__prometheus::Histogram::with_opts(
+            $crate::__prometheus::HistogramOpts::new($name, $help).buckets($buckets),
+        )
+        .and_then(|metric| $crate::__register_in!($registry, metric))
+    };
+}
+
+/// the buckets default to `prometheus::DEFAULT_BUCKETS`
+#[macro_export]
+macro_rules! register_histogram_vec_in {
+    ($registry:expr, $name:expr, $help:expr, $labels:expr $(,)?) => {
+        $crate::register_histogram_vec_in!(
+            $registry,
+            $name,
+            $help,
+            $labels,
+            $crate::__prometheus::DEFAULT_BUCKETS.to_vec()
+        )
+    };
+    ($registry:expr, $name:expr, $help:expr, $labels:expr, $buckets:expr $(,)?) => {
+        $crate::__prometheus::HistogramVec::new(
+            $crate::__prometheus::HistogramOpts::new($name, $help).buckets($buckets),
+            $labels,
+        )
+        .and_then(|metric| $crate::__register_in!($registry, metric))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus::Registry;
+
+    #[test]
+    fn test_register_in() {
+        let registry = Registry::new();
+        let counter = register_int_counter_in!(registry, "requests", "test").unwrap();
+        counter.inc();
+        let gauges = register_gauge_vec_in!(registry, "temperature", "test", &["room"]).unwrap();
+        gauges.with_label_values(&["kitchen"]).set(21.5);
+        let histogram =
+            register_histogram_in!(registry, "latency", "test", vec![0.1, 1.0]).unwrap();
+        histogram.observe(0.5);
+        let histograms =
+            register_histogram_vec_in!(registry, "size", "test", &["method"],).unwrap();
+        histograms.with_label_values(&["GET"]).observe(2.0);
+
+        let families = registry.gather();
+        let names = families.iter().map(|mf| mf.get_name()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["latency", "requests", "size", "temperature"]);
+        assert_eq!(
+            families[0].get_metric()[0]
+                .get_histogram()
+                .get_bucket()
+                .len(),
+            2
+        );
+        assert_eq!(families[1].get_metric()[0].get_counter().get_value(), 1.0);
+        assert_eq!(
+            families[2].get_metric()[0]
+                .get_histogram()
+                .get_bucket()
+                .len(),
+            11
+        );
+
+        assert!(register_counter_in!(registry, "requests", "test").is_err());
+        assert!(register_int_gauge_in!(registry, "invalid name", "test").is_err());
+    }
+}
@@ -0,0 +1,102 @@
+/// One entry of an `Accept` like header, e.g. `text/plain; version=0.0.4; q=0.5`
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Preference {
+    pub value: String,
+    pub params: Vec<(String, String)>,
+    pub q: f32,
+}
+
+impl Preference {
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// `text/plain` matches `text/plain`, `text/*` and `*/*`
+    pub fn matches(&self, value: &str) -> bool {
+        if self.value == "*/*" || self.value == "*" || self.value == value {
+            return true;
+        }
+        match (self.value.split('/').next(), value.split('/').next()) {
+            (Some(a), Some(b)) => self.value.ends_with("/*") && a == b,
+            _ => false,
+        }
+    }
+}
+
+/// Parse all values of an `Accept` like header, values and parameter names
+/// are lower cased, a missing or malformed `q` counts as 1.
+pub(crate) fn preferences(values: &[&str]) -> Vec<Preference> {
+    values
+        .iter()
+        .flat_map(|v| v.split(','))
+        .filter_map(|item| {
+            let mut parts = item.split(';').map(str::trim);
+            let value = parts.next()?.to_ascii_lowercase();
+            if value.is_empty() {
+                return None;
+            }
+            let mut params = vec![];
+            let mut q = 1.0;
+            for param in parts {
+                let mut kv = param.splitn(2, '=');
+                let k = kv.next().unwrap().trim().to_ascii_lowercase();
+                let v = kv.next().unwrap_or("").trim().trim_matches('"').to_string();
+                if k == "q" {
+                    q = v
+                        .parse::<f32>()
+                        .ok()
+                        .filter(|q| q.is_finite())
+                        .unwrap_or(1.0)
+                        .clamp(0.0, 1.0);
+                } else {
+                    params.push((k, v));
+                }
+            }
+            Some(Preference { value, params, q })
+        })
+        .collect()
+}
+
+/// The q value the client gives to `value`, the most specific matching entry
+/// wins, None if nothing matches
+pub(crate) fn quality<F>(prefs: &[Preference], value: &str, accept: F) -> Option<f32>
+where
+    F: Fn(&Preference) -> bool,
+{
+    prefs
+        .iter()
+        .filter(|p| p.matches(value) && accept(p))
+        .max_by_key(|p| p.value.matches('*').count() == 0)
+        .map(|p| p.q)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{preferences, quality};
+
+    #[test]
+    fn test_preferences() {
+        let prefs = preferences(&[
+            "application/openmetrics-text;version=1.0.0,text/plain;version=0.0.4;q=0.5",
+            "*/*;q=0.1",
+        ]);
+        assert_eq!(prefs.len(), 3);
+        assert_eq!(prefs[0].value, "application/openmetrics-text");
+        assert_eq!(prefs[0].param("version"), Some("1.0.0"));
+        assert_eq!(prefs[0].q, 1.0);
+        assert_eq!(prefs[1].q, 0.5);
+
+        assert_eq!(quality(&prefs, "text/plain", |_| true), Some(0.5));
+        assert_eq!(quality(&prefs, "application/json", |_| true), Some(0.1));
+        assert_eq!(
+            quality(&prefs, "application/openmetrics-text", |p| {
+                p.param("version") == Some("0.0.1")
+            }),
+            None
+        );
+        assert_eq!(quality(&[], "text/plain", |_| true), None);
+    }
+}
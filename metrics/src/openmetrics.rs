@@ -0,0 +1,499 @@
+use prometheus::{
+    proto::{LabelPair, Metric, MetricFamily, MetricType},
+    Encoder, Result,
+};
+use std::{
+    collections::HashMap,
+    io::Write,
+    sync::{Arc, Mutex},
+};
+
+pub const OPENMETRICS_FORMAT: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+// base units from the OpenMetrics spec, a family whose name ends with
+// `_<unit>` gets a `# UNIT` line
+const UNITS: &[&str] = &[
+    "seconds", "bytes", "ratio", "meters", "grams", "volts", "amperes", "joules", "celsius",
+];
+
+// exemplars kept per series, histograms attach the latest one of each bucket
+const MAX_EXEMPLARS: usize = 16;
+
+// series with exemplars kept by default, see `Exemplars::max_series`
+const DEFAULT_MAX_SERIES: usize = 1024;
+
+/// An exemplar, e.g. the trace id of one request counted by a series
+#[derive(Debug, Clone, PartialEq)]
+pub struct Exemplar {
+    labels: Vec<(String, String)>,
+    value: f64,
+    timestamp: Option<f64>,
+}
+
+impl Exemplar {
+    pub fn new<S: Into<String>>(labels: Vec<(S, S)>, value: f64) -> Self {
+        Exemplar {
+            labels: labels
+                .into_iter()
+                .map(|(k, v)| (k.into(), v.into()))
+                .collect(),
+            value,
+            timestamp: None,
+        }
+    }
+
+    /// unix time in seconds
+    pub fn timestamp(mut self, timestamp: f64) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+}
+
+type SeriesKey = (String, Vec<(String, String)>);
+
+#[derive(Debug, Default)]
+struct Store {
+    // exemplars of every series and when the series last recorded one
+    series: HashMap<SeriesKey, (u64, Vec<Exemplar>)>,
+    seq: u64,
+}
+
+/// Exemplars recorded by the application, keyed by family name as registered
+/// and the label set of the series. Counters expose the latest exemplar on
+/// `_total`, histograms the latest one falling into each bucket.
+///
+/// At most `max_series` series are kept, recording into a new series beyond
+/// that drops the series which recorded least recently.
+#[derive(Debug, Clone)]
+pub struct Exemplars {
+    store: Arc<Mutex<Store>>,
+    max_series: usize,
+}
+
+impl Default for Exemplars {
+    fn default() -> Self {
+        Exemplars {
+            store: Arc::default(),
+            max_series: DEFAULT_MAX_SERIES,
+        }
+    }
+}
+
+impl Exemplars {
+    pub fn new() -> Self {
+        Exemplars::default()
+    }
+
+    /// 1024 by default
+    pub fn max_series(mut self, max_series: usize) -> Self {
+        self.max_series = max_series;
+        self
+    }
+
+    pub fn record(&self, name: &str, labels: &[(&str, &str)], exemplar: Exemplar) {
+        let mut labels: Vec<(String, String)> = labels
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        labels.sort();
+        let key = (name.to_string(), labels);
+        let mut store = self.store.lock().unwrap();
+        store.seq += 1;
+        let seq = store.seq;
+        if !store.series.contains_key(&key) && store.series.len() >= self.max_series {
+            let oldest = store
+                .series
+                .iter()
+                .min_by_key(|(_, (used, _))| *used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                store.series.remove(&oldest);
+            }
+        }
+        let (used, exemplars) = store.series.entry(key).or_default();
+        *used = seq;
+        if exemplars.len() == MAX_EXEMPLARS {
+            exemplars.remove(0);
+        }
+        exemplars.push(exemplar);
+    }
+
+    fn get(&self, name: &str, labels: &[LabelPair]) -> Vec<Exemplar> {
+        let mut labels: Vec<(String, String)> = labels
+            .iter()
+            .map(|l| (l.get_name().to_string(), l.get_value().to_string()))
+            .collect();
+        labels.sort();
+        let store = self.store.lock().unwrap();
+        store
+            .series
+            .get(&(name.to_string(), labels))
+            .map(|(_, exemplars)| exemplars.clone())
+            .unwrap_or_default()
+    }
+}
+
+/// An implementation of an [`Encoder`](::Encoder) that converts a `MetricFamily` proto message
+/// into the OpenMetrics 1.0.0 text format
+///
+/// Counters are exposed as `name_total` and the exposition ends with `# EOF`.
+/// There is no unit in the proto message, it is inferred from the name suffix.
+#[derive(Debug, Default)]
+pub struct OpenMetricsEncoder {
+    exemplars: Option<Exemplars>,
+}
+
+impl OpenMetricsEncoder {
+    pub fn new() -> Self {
+        OpenMetricsEncoder::default()
+    }
+
+    pub fn exemplars(mut self, exemplars: Exemplars) -> Self {
+        self.exemplars = Some(exemplars);
+        self
+    }
+
+    fn exemplars_of(&self, name: &str, m: &Metric) -> Vec<Exemplar> {
+        match &self.exemplars {
+            Some(exemplars) => exemplars.get(name, m.get_label()),
+            None => vec![],
+        }
+    }
+}
+
+impl Encoder for OpenMetricsEncoder {
+    fn encode<W: Write>(&self, metric_familys: &[MetricFamily], writer: &mut W) -> Result<()> {
+        for mf in metric_familys {
+            let metric_type = mf.get_field_type();
+            let name = match metric_type {
+                MetricType::COUNTER => mf
+                    .get_name()
+                    .strip_suffix("_total")
+                    .unwrap_or(mf.get_name()),
+                _ => mf.get_name(),
+            };
+            let type_name = match metric_type {
+                MetricType::COUNTER => "counter",
+                MetricType::GAUGE => "gauge",
+                MetricType::SUMMARY => "summary",
+                MetricType::UNTYPED => "unknown",
+                MetricType::HISTOGRAM => "histogram",
+            };
+
+            writeln!(writer, "# TYPE {} {}", name, type_name)?;
+            if let Some(unit) = UNITS.iter().find(|u| name.ends_with(&format!("_{}", u))) {
+                writeln!(writer, "# UNIT {} {}", name, unit)?;
+            }
+            if !mf.get_help().is_empty() {
+                writeln!(writer, "# HELP {} {}", name, escape(mf.get_help()))?;
+            }
+
+            for m in mf.get_metric() {
+                match metric_type {
+                    MetricType::COUNTER => {
+                        let exemplar = self.exemplars_of(mf.get_name(), m).pop();
+                        let total = format!("{}_total", name);
+                        write_sample(
+                            writer,
+                            m,
+                            &total,
+                            None,
+                            m.get_counter().get_value(),
+                            exemplar,
+                        )?;
+                    }
+                    MetricType::GAUGE => {
+                        write_sample(writer, m, name, None, m.get_gauge().get_value(), None)?;
+                    }
+                    MetricType::UNTYPED => {
+                        write_sample(writer, m, name, None, m.get_untyped().get_value(), None)?;
+                    }
+                    MetricType::HISTOGRAM => {
+                        let h = m.get_histogram();
+                        let exemplars = self.exemplars_of(mf.get_name(), m);
+                        let bucket = format!("{}_bucket", name);
+                        let mut lower = f64::NEG_INFINITY;
+                        let mut bounds: Vec<(f64, u64)> = h
+                            .get_bucket()
+                            .iter()
+                            .map(|b| (b.get_upper_bound(), b.get_cumulative_count()))
+                            .collect();
+                        if bounds.last().is_none_or(|b| b.0 != f64::INFINITY) {
+                            bounds.push((f64::INFINITY, h.get_sample_count()));
+                        }
+                        for (upper, count) in bounds {
+                            let exemplar = exemplars
+                                .iter()
+                                .rev()
+                                .find(|e| e.value > lower && e.value <= upper)
+                                .cloned();
+                            let le = format_float(upper);
+                            write_sample(
+                                writer,
+                                m,
+                                &bucket,
+                                Some(("le", &le)),
+                                count as f64,
+                                exemplar,
+                            )?;
+                            lower = upper;
+                        }
+                        write_sample(
+                            writer,
+                            m,
+                            &format!("{}_count", name),
+                            None,
+                            h.get_sample_count() as f64,
+                            None,
+                        )?;
+                        write_sample(
+                            writer,
+                            m,
+                            &format!("{}_sum", name),
+                            None,
+                            h.get_sample_sum(),
+                            None,
+                        )?;
+                    }
+                    MetricType::SUMMARY => {
+                        let s = m.get_summary();
+                        for q in s.get_quantile() {
+                            let quantile = format_float(q.get_quantile());
+                            write_sample(
+                                writer,
+                                m,
+                                name,
+                                Some(("quantile", &quantile)),
+                                q.get_value(),
+                                None,
+                            )?;
+                        }
+                        write_sample(
+                            writer,
+                            m,
+                            &format!("{}_count", name),
+                            None,
+                            s.get_sample_count() as f64,
+                            None,
+                        )?;
+                        write_sample(
+                            writer,
+                            m,
+                            &format!("{}_sum", name),
+                            None,
+                            s.get_sample_sum(),
+                            None,
+                        )?;
+                    }
+                }
+            }
+        }
+
+        writer.write_all(b"# EOF\n")?;
+        Ok(())
+    }
+
+    fn format_type(&self) -> &str {
+        OPENMETRICS_FORMAT
+    }
+}
+
+/// `write_sample` writes one sample line with an optional extra label
+/// (`le` or `quantile`) and an optional exemplar
+fn write_sample<W: Write>(
+    writer: &mut W,
+    metric: &Metric,
+    name: &str,
+    extra_label: Option<(&str, &str)>,
+    value: f64,
+    exemplar: Option<Exemplar>,
+) -> Result<()> {
+    let labels = metric
+        .get_label()
+        .iter()
+        .map(|l| (l.get_name(), l.get_value()))
+        .chain(extra_label);
+    writer.write_all(name.as_bytes())?;
+    write_labels(labels, writer)?;
+    write!(writer, " {}", format_float(value))?;
+    if metric.has_timestamp_ms() {
+        write!(writer, " {}", metric.get_timestamp_ms() as f64 / 1000.0)?;
+    }
+    if let Some(exemplar) = exemplar {
+        writer.write_all(b" # ")?;
+        if exemplar.labels.is_empty() {
+            writer.write_all(b"{}")?;
+        }
+        let labels = exemplar
+            .labels
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()));
+        write_labels(labels, writer)?;
+        write!(writer, " {}", format_float(exemplar.value))?;
+        if let Some(timestamp) = exemplar.timestamp {
+            write!(writer, " {}", timestamp)?;
+        }
+    }
+    writer.write_all(b"\n")?;
+    Ok(())
+}
+
+fn write_labels<'a, I, W>(labels: I, writer: &mut W) -> Result<()>
+where
+    I: Iterator<Item = (&'a str, &'a str)>,
+    W: Write,
+{
+    let mut separator = "{";
+    for (name, value) in labels {
+        write!(writer, "{}{}=\"{}\"", separator, name, escape(value))?;
+        separator = ",";
+    }
+    if separator == "," {
+        writer.write_all(b"}")?;
+    }
+    Ok(())
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('\n', r"\n")
+        .replace('"', r#"\""#)
+}
+
+// OpenMetrics wants canonical floats for `le` and `quantile`, i.e. `1.0` rather than `1`
+fn format_float(value: f64) -> String {
+    if value == f64::INFINITY {
+        "+Inf".to_string()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".to_string()
+    } else if value.is_nan() {
+        "NaN".to_string()
+    } else if value.fract() == 0.0 && value.abs() < 1e15 {
+        format!("{:.1}", value)
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Exemplar, Exemplars, OpenMetricsEncoder};
+    use prometheus::{
+        Counter, Encoder, Gauge, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry,
+    };
+
+    fn encode(encoder: &OpenMetricsEncoder, registry: &Registry) -> String {
+        let mut buffer = vec![];
+        encoder.encode(&registry.gather(), &mut buffer).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    #[test]
+    fn test_encode() {
+        let registry = Registry::new();
+        let counter = IntCounterVec::new(
+            Opts::new("requests_total", "Total \"requests\"\nserved"),
+            &["method"],
+        )
+        .unwrap();
+        counter.with_label_values(&["GET"]).inc_by(3);
+        registry.register(Box::new(counter)).unwrap();
+        let gauge = Gauge::new("memory_bytes", "Memory usage").unwrap();
+        gauge.set(1024.0);
+        registry.register(Box::new(gauge)).unwrap();
+
+        assert_eq!(
+            encode(&OpenMetricsEncoder::new(), &registry),
+            r#"# TYPE memory_bytes gauge
+# UNIT memory_bytes bytes
+# HELP memory_bytes Memory usage
+memory_bytes 1024.0
+# TYPE requests counter
+# HELP requests Total \"requests\"\nserved
+requests_total{method="GET"} 3.0
+# EOF
+"#
+        );
+    }
+
+    #[test]
+    fn test_strip_total_once() {
+        let registry = Registry::new();
+        let counter = Counter::new("retries_total_total", "Retries").unwrap();
+        counter.inc();
+        registry.register(Box::new(counter)).unwrap();
+        let exemplars = Exemplars::new();
+        exemplars.record(
+            "retries_total_total",
+            &[],
+            Exemplar::new(vec![("trace_id", "a")], 1.0),
+        );
+
+        let text = encode(&OpenMetricsEncoder::new().exemplars(exemplars), &registry);
+        assert!(text.starts_with("# TYPE retries_total counter\n"));
+        assert!(text.contains("retries_total_total 1.0 # {trace_id=\"a\"} 1.0\n"));
+    }
+
+    #[test]
+    fn test_max_series() {
+        let exemplars = Exemplars::new().max_series(2);
+        let record = |name: &str| {
+            exemplars.record(name, &[], Exemplar::new(vec![("trace_id", name)], 1.0));
+        };
+        record("a");
+        record("b");
+        record("a");
+        record("c");
+        let labels = [];
+        assert_eq!(exemplars.get("a", &labels).len(), 2);
+        assert!(exemplars.get("b", &labels).is_empty());
+        assert_eq!(exemplars.get("c", &labels).len(), 1);
+    }
+
+    #[test]
+    fn test_exemplars() {
+        let registry = Registry::new();
+        let counter = Counter::new("jobs", "Jobs").unwrap();
+        counter.inc();
+        registry.register(Box::new(counter)).unwrap();
+        let histogram = HistogramVec::new(
+            HistogramOpts::new("latency_seconds", "Latency").buckets(vec![0.1, 1.0]),
+            &["method"],
+        )
+        .unwrap();
+        histogram.with_label_values(&["GET"]).observe(0.05);
+        histogram.with_label_values(&["GET"]).observe(0.5);
+        registry.register(Box::new(histogram)).unwrap();
+
+        let exemplars = Exemplars::new();
+        exemplars.record("jobs", &[], Exemplar::new(vec![("trace_id", "a")], 1.0));
+        exemplars.record(
+            "latency_seconds",
+            &[("method", "GET")],
+            Exemplar::new(vec![("trace_id", "b")], 0.05).timestamp(1520879607.789),
+        );
+        exemplars.record(
+            "latency_seconds",
+            &[("method", "GET")],
+            Exemplar::new(vec![("trace_id", "c")], 0.5),
+        );
+
+        assert_eq!(
+            encode(&OpenMetricsEncoder::new().exemplars(exemplars), &registry),
+            r#"# TYPE jobs counter
+# HELP jobs Jobs
+jobs_total 1.0 # {trace_id="a"} 1.0
+# TYPE latency_seconds histogram
+# UNIT latency_seconds seconds
+# HELP latency_seconds Latency
+latency_seconds_bucket{method="GET",le="0.1"} 1.0 # {trace_id="b"} 0.05 1520879607.789
+latency_seconds_bucket{method="GET",le="1.0"} 2.0 # {trace_id="c"} 0.5
+latency_seconds_bucket{method="GET",le="+Inf"} 2.0
+latency_seconds_count{method="GET"} 2.0
+latency_seconds_sum{method="GET"} 0.55
+# EOF
+"#
+        );
+    }
+}
@@ -0,0 +1,352 @@
+use prometheus::{
+    core::{Collector, Desc},
+    proto::{Counter, Gauge, LabelPair, Metric, MetricFamily, MetricType},
+    Registry, Result,
+};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// ProcessCollector exports cpu, memory, file descriptor, thread and context
+/// switch metrics of the current process read from procfs, every scrape
+/// reads procfs again and a metric whose file can't be read is skipped.
+pub struct ProcessCollector {
+    proc_root: PathBuf,
+    clock_ticks: f64,
+    page_size: f64,
+    descs: Vec<Desc>,
+}
+
+impl ProcessCollector {
+    pub fn new() -> Self {
+        let descs = FAMILIES
+            .iter()
+            .map(|family| {
+                let labels = family.labels.iter().map(|l| l.to_string()).collect();
+                Desc::new(
+                    family.name.to_string(),
+                    family.help.to_string(),
+                    labels,
+                    HashMap::new(),
+                )
+                .unwrap()
+            })
+            .collect();
+        ProcessCollector {
+            proc_root: PathBuf::from("/proc"),
+            clock_ticks: unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as f64,
+            page_size: unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as f64,
+            descs,
+        }
+    }
+
+    /// read `<proc_root>/self/*` and `<proc_root>/stat` instead of `/proc`
+    pub fn proc_root<P: Into<PathBuf>>(mut self, proc_root: P) -> Self {
+        self.proc_root = proc_root.into();
+        self
+    }
+
+    pub fn clock_ticks(mut self, clock_ticks: u64) -> Self {
+        self.clock_ticks = clock_ticks as f64;
+        self
+    }
+
+    pub fn page_size(mut self, page_size: u64) -> Self {
+        self.page_size = page_size as f64;
+        self
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.proc_root.join("self").join(name)
+    }
+}
+
+impl Default for ProcessCollector {
+    fn default() -> Self {
+        ProcessCollector::new()
+    }
+}
+
+/// name, help, type and variable labels of one exported family
+struct Family {
+    name: &'static str,
+    help: &'static str,
+    metric_type: MetricType,
+    labels: &'static [&'static str],
+}
+
+const CPU_SECONDS: Family = Family {
+    name: "process_cpu_seconds_total",
+    help: "Total user and system CPU time spent in seconds.",
+    metric_type: MetricType::COUNTER,
+    labels: &[],
+};
+
+const RESIDENT_MEMORY: Family = Family {
+    name: "process_resident_memory_bytes",
+    help: "Resident memory size in bytes.",
+    metric_type: MetricType::GAUGE,
+    labels: &[],
+};
+
+const VIRTUAL_MEMORY: Family = Family {
+    name: "process_virtual_memory_bytes",
+    help: "Virtual memory size in bytes.",
+    metric_type: MetricType::GAUGE,
+    labels: &[],
+};
+
+const OPEN_FDS: Family = Family {
+    name: "process_open_fds",
+    help: "Number of open file descriptors.",
+    metric_type: MetricType::GAUGE,
+    labels: &[],
+};
+
+const MAX_FDS: Family = Family {
+    name: "process_max_fds",
+    help: "Maximum number of open file descriptors.",
+    metric_type: MetricType::GAUGE,
+    labels: &[],
+};
+
+const THREADS: Family = Family {
+    name: "process_threads",
+    help: "Number of OS threads in the process.",
+    metric_type: MetricType::GAUGE,
+    labels: &[],
+};
+
+const START_TIME: Family = Family {
+    name: "process_start_time_seconds",
+    help: "Start time of the process since unix epoch in seconds.",
+    metric_type: MetricType::GAUGE,
+    labels: &[],
+};
+
+const CONTEXT_SWITCHES: Family = Family {
+    name: "process_context_switches_total",
+    help: "Number of context switches.",
+    metric_type: MetricType::COUNTER,
+    labels: &["type"],
+};
+
+// every exported family, for the descs
+const FAMILIES: &[Family] = &[
+    CPU_SECONDS,
+    RESIDENT_MEMORY,
+    VIRTUAL_MEMORY,
+    OPEN_FDS,
+    MAX_FDS,
+    THREADS,
+    START_TIME,
+    CONTEXT_SWITCHES,
+];
+
+// value and labels of one sample
+type Sample = (f64, &'static [(&'static str, &'static str)]);
+
+impl Family {
+    fn metric_family(&self, samples: Vec<Sample>) -> MetricFamily {
+        let mut mf = MetricFamily::new();
+        mf.set_name(self.name.to_string());
+        mf.set_help(self.help.to_string());
+        mf.set_field_type(self.metric_type);
+        for (value, labels) in samples {
+            mf.mut_metric()
+                .push(metric(value, self.metric_type, labels));
+        }
+        mf
+    }
+}
+
+fn unlabelled(value: Option<f64>) -> Vec<Sample> {
+    value.map(|value| (value, &[][..])).into_iter().collect()
+}
+
+/// fields of `/proc/self/stat` after the command name, the first one is the
+/// state which is field 3 in proc(5)
+struct Stat {
+    utime: f64,
+    stime: f64,
+    num_threads: f64,
+    starttime: f64,
+    vsize: f64,
+    rss: f64,
+}
+
+fn parse_stat(content: &str) -> Option<Stat> {
+    // the command name is in parentheses and may contain spaces
+    let rest = &content[content.rfind(')')? + 1..];
+    let fields: Vec<&str> = rest.split_whitespace().collect();
+    let field = |n: usize| -> Option<f64> { fields.get(n - 3)?.parse().ok() };
+    Some(Stat {
+        utime: field(14)?,
+        stime: field(15)?,
+        num_threads: field(20)?,
+        starttime: field(22)?,
+        vsize: field(23)?,
+        rss: field(24)?,
+    })
+}
+
+// the first number after `key` on the line starting with `key`
+fn find_value(content: &str, key: &str) -> Option<f64> {
+    content
+        .lines()
+        .find(|line| line.starts_with(key))
+        .and_then(|line| line[key.len()..].split_whitespace().next())
+        .and_then(|value| value.parse().ok())
+}
+
+fn count_entries(path: &Path) -> Option<f64> {
+    Some(fs::read_dir(path).ok()?.count() as f64)
+}
+
+fn metric(value: f64, metric_type: MetricType, labels: &[(&str, &str)]) -> Metric {
+    let mut m = Metric::new();
+    for (name, value) in labels {
+        let mut label = LabelPair::new();
+        label.set_name(name.to_string());
+        label.set_value(value.to_string());
+        m.mut_label().push(label);
+    }
+    if metric_type == MetricType::COUNTER {
+        let mut counter = Counter::new();
+        counter.set_value(value);
+        m.set_counter(counter);
+    } else {
+        let mut gauge = Gauge::new();
+        gauge.set_value(value);
+        m.set_gauge(gauge);
+    }
+    m
+}
+
+impl Collector for ProcessCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let stat = fs::read_to_string(self.path("stat"))
+            .ok()
+            .and_then(|content| parse_stat(&content));
+        let status = fs::read_to_string(self.path("status")).unwrap_or_default();
+        let limits = fs::read_to_string(self.path("limits")).unwrap_or_default();
+        let boot_time = fs::read_to_string(self.proc_root.join("stat"))
+            .ok()
+            .and_then(|content| find_value(&content, "btime"));
+
+        let cpu = stat
+            .as_ref()
+            .map(|stat| (stat.utime + stat.stime) / self.clock_ticks);
+        let start_time = stat
+            .as_ref()
+            .zip(boot_time)
+            .map(|(stat, boot_time)| boot_time + stat.starttime / self.clock_ticks);
+        let mut context_switches: Vec<Sample> = vec![];
+        if let Some(voluntary) = find_value(&status, "voluntary_ctxt_switches:") {
+            context_switches.push((voluntary, &[("type", "voluntary")]));
+        }
+        if let Some(nonvoluntary) = find_value(&status, "nonvoluntary_ctxt_switches:") {
+            context_switches.push((nonvoluntary, &[("type", "nonvoluntary")]));
+        }
+
+        let samples = vec![
+            (CPU_SECONDS, unlabelled(cpu)),
+            (
+                RESIDENT_MEMORY,
+                unlabelled(stat.as_ref().map(|stat| stat.rss * self.page_size)),
+            ),
+            (
+                VIRTUAL_MEMORY,
+                unlabelled(stat.as_ref().map(|stat| stat.vsize)),
+            ),
+            (OPEN_FDS, unlabelled(count_entries(&self.path("fd")))),
+            (MAX_FDS, unlabelled(find_value(&limits, "Max open files"))),
+            (
+                THREADS,
+                unlabelled(stat.as_ref().map(|stat| stat.num_threads)),
+            ),
+            (START_TIME, unlabelled(start_time)),
+            (CONTEXT_SWITCHES, context_switches),
+        ];
+        samples
+            .into_iter()
+            .filter(|(_, samples)| !samples.is_empty())
+            .map(|(family, samples)| family.metric_family(samples))
+            .collect()
+    }
+}
+
+/// register a ProcessCollector reading `/proc` into `registry`
+pub fn register_process_collector(registry: &Registry) -> Result<()> {
+    registry.register(Box::new(ProcessCollector::new()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{register_process_collector, ProcessCollector};
+    use prometheus::{core::Collector, Registry};
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_collect_fixture() {
+        let collector = ProcessCollector::new()
+            .proc_root(concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/proc"))
+            .clock_ticks(100)
+            .page_size(4096);
+        let values: HashMap<String, f64> = collector
+            .collect()
+            .iter()
+            .flat_map(|mf| {
+                mf.get_metric().iter().map(move |m| {
+                    let mut name = mf.get_name().to_string();
+                    for label in m.get_label() {
+                        name = format!("{}.{}", name, label.get_value());
+                    }
+                    let value = if m.has_counter() {
+                        m.get_counter().get_value()
+                    } else {
+                        m.get_gauge().get_value()
+                    };
+                    (name, value)
+                })
+            })
+            .collect();
+
+        assert_eq!(values["process_cpu_seconds_total"], 4.0);
+        assert_eq!(values["process_resident_memory_bytes"], 10485760.0);
+        assert_eq!(values["process_virtual_memory_bytes"], 104857600.0);
+        assert_eq!(values["process_open_fds"], 4.0);
+        assert_eq!(values["process_max_fds"], 1024.0);
+        assert_eq!(values["process_threads"], 7.0);
+        assert_eq!(values["process_start_time_seconds"], 1700000050.0);
+        assert_eq!(values["process_context_switches_total.voluntary"], 150.0);
+        assert_eq!(values["process_context_switches_total.nonvoluntary"], 12.0);
+        assert_eq!(values.len(), 9);
+    }
+
+    #[test]
+    fn test_missing_proc() {
+        let collector = ProcessCollector::new().proc_root("/nonexistent");
+        assert!(collector.collect().is_empty());
+    }
+
+    #[test]
+    fn test_register() {
+        let registry = Registry::new();
+        register_process_collector(&registry).unwrap();
+        let names = registry
+            .gather()
+            .iter()
+            .map(|mf| mf.get_name().to_string())
+            .collect::<Vec<_>>();
+        assert!(names.contains(&"process_cpu_seconds_total".to_string()));
+        assert!(names.contains(&"process_open_fds".to_string()));
+        assert!(register_process_collector(&registry).is_err());
+    }
+}
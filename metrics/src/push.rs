@@ -0,0 +1,357 @@
+use crate::error::PushError;
+use crate::source::MetricSources;
+use base64::{engine::general_purpose::URL_SAFE, Engine};
+use futures::{
+    future::{self, Either},
+    pin_mut, Future,
+};
+use hyper::{
+    body, client::HttpConnector, header::CONTENT_TYPE, Body, Client, Method, Request, Uri,
+};
+use prometheus::{Encoder, TextEncoder};
+use std::{sync::Arc, time::Duration};
+use tokio::time::{self, MissedTickBehavior};
+
+/// PushClient sends the gathered metrics to a Pushgateway compatible endpoint,
+/// for jobs which exit before they could be scraped.
+///
+/// Metrics are pushed to `<url>/metrics/job/<job>/<label>/<value>...`, where a
+/// job or value which is empty or contains `/` is sent as `@base64`. A request
+/// failing with a connection error or a 5xx status is retried with exponential
+/// backoff.
+#[derive(Clone)]
+pub struct PushClient {
+    url: String,
+    job: String,
+    grouping: Vec<(String, String)>,
+    sources: Arc<MetricSources>,
+    retries: usize,
+    backoff: Duration,
+    max_backoff: Duration,
+    client: Client<HttpConnector>,
+}
+
+impl PushClient {
+    /// `url` is the address of the gateway, e.g. `http://pushgateway:9091`
+    pub fn new<S: Into<String>>(url: &str, job: S, sources: MetricSources) -> Self {
+        PushClient {
+            url: url.trim_end_matches('/').to_string(),
+            job: job.into(),
+            grouping: vec![],
+            sources: Arc::new(sources),
+            retries: 3,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            client: Client::new(),
+        }
+    }
+
+    /// label identifying the group together with the job, e.g. `instance`
+    pub fn grouping<S: Into<String>>(mut self, name: S, value: S) -> Self {
+        self.grouping.push((name.into(), value.into()));
+        self
+    }
+
+    /// retries after the first attempt
+    pub fn retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// wait `initial` before the first retry and double it for every next
+    /// retry, up to `max`
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// PUT, replace all metrics of the group
+    pub async fn push(&self) -> Result<(), PushError> {
+        self.send_metrics(Method::PUT).await
+    }
+
+    /// POST, replace only the families which are pushed
+    pub async fn push_add(&self) -> Result<(), PushError> {
+        self.send_metrics(Method::POST).await
+    }
+
+    /// DELETE all metrics of the group
+    pub async fn delete(&self) -> Result<(), PushError> {
+        let uri = self.uri()?;
+        self.send(Method::DELETE, uri, vec![]).await
+    }
+
+    /// PUT every `interval` until `shutdown` resolves, then push a last time so
+    /// the final values aren't lost. A failed push is passed to `on_error` and
+    /// doesn't stop the loop.
+    pub async fn push_periodically<S, F>(self, interval: Duration, shutdown: S, on_error: F)
+    where
+        S: Future<Output = ()>,
+        F: Fn(PushError),
+    {
+        let mut ticks = time::interval_at(time::Instant::now() + interval, interval);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        pin_mut!(shutdown);
+        loop {
+            let tick = ticks.tick();
+            pin_mut!(tick);
+            match future::select(tick, shutdown.as_mut()).await {
+                Either::Left(_) => {
+                    if let Err(e) = self.push().await {
+                        on_error(e);
+                    }
+                }
+                Either::Right(_) => break,
+            }
+        }
+        if let Err(e) = self.push().await {
+            on_error(e);
+        }
+    }
+
+    async fn send_metrics(&self, method: Method) -> Result<(), PushError> {
+        let uri = self.uri()?;
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&self.sources.gather(), &mut buffer)
+            .map_err(PushError::Encode)?;
+        self.send(method, uri, buffer).await
+    }
+
+    fn uri(&self) -> Result<Uri, PushError> {
+        let mut url = format!("{}/metrics/{}", self.url, label_segments("job", &self.job));
+        for (name, value) in &self.grouping {
+            url = format!("{}/{}", url, label_segments(name, value));
+        }
+        url.parse()
+            .map_err(|e| PushError::InvalidUrl(format!("{}: {}", url, e)))
+    }
+
+    async fn send(&self, method: Method, uri: Uri, body: Vec<u8>) -> Result<(), PushError> {
+        let mut backoff = self.backoff;
+        let mut attempt = 0;
+        loop {
+            match self
+                .send_once(method.clone(), uri.clone(), body.clone())
+                .await
+            {
+                Err(ref e) if attempt < self.retries && e.is_retryable() => {
+                    time::sleep(backoff).await;
+                    attempt += 1;
+                    backoff = (backoff * 2).min(self.max_backoff);
+                }
+                res => return res,
+            }
+        }
+    }
+
+    async fn send_once(&self, method: Method, uri: Uri, body: Vec<u8>) -> Result<(), PushError> {
+        let req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(CONTENT_TYPE, TextEncoder::new().format_type())
+            .body(Body::from(body))
+            .unwrap();
+        let resp = self.client.request(req).await.map_err(PushError::Http)?;
+        let status = resp.status();
+        let body = body::to_bytes(resp.into_body())
+            .await
+            .map_err(PushError::Http)?;
+        if status.is_success() {
+            Ok(())
+        } else {
+            Err(PushError::Status {
+                status,
+                body: String::from_utf8_lossy(&body).into_owned(),
+            })
+        }
+    }
+}
+
+// `<name>/<value>`, or `<name>@base64/<value>` for a value which can't be a
+// path segment
+fn label_segments(name: &str, value: &str) -> String {
+    if value.is_empty() || value.contains('/') {
+        format!("{}@base64/{}", name, base64_url(value.as_bytes()))
+    } else {
+        format!("{}/{}", name, path_segment(value))
+    }
+}
+
+fn path_segment(value: &str) -> String {
+    form_urlencoded::byte_serialize(value.as_bytes())
+        .collect::<String>()
+        .replace('+', "%20")
+}
+
+// url safe base64 with padding, which the Pushgateway expects for label
+// values containing `/` or being empty
+fn base64_url(input: &[u8]) -> String {
+    if input.is_empty() {
+        return "=".to_string();
+    }
+    URL_SAFE.encode(input)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{base64_url, PushClient};
+    use crate::error::PushError;
+    use crate::server::tests::test_sources;
+    use futures::{channel::oneshot, FutureExt};
+    use hyper::{
+        body,
+        service::{make_service_fn, service_fn},
+        Body, Request, Response, Server, StatusCode,
+    };
+    use std::{
+        convert::Infallible,
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    type Received = Arc<Mutex<Vec<(String, String, String)>>>;
+
+    // stands in for the gateway, answers the first `failures` requests with 503
+    fn gateway(failures: usize) -> (SocketAddr, Received) {
+        let received: Received = Arc::new(Mutex::new(vec![]));
+        let log = received.clone();
+        let make_service = make_service_fn(move |_| {
+            let log = log.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let log = log.clone();
+                    async move {
+                        let method = req.method().to_string();
+                        let path = req.uri().path().to_string();
+                        let body = body::to_bytes(req.into_body()).await?;
+                        let mut log = log.lock().unwrap();
+                        log.push((method, path, String::from_utf8_lossy(&body).into_owned()));
+                        let status = if log.len() <= failures {
+                            StatusCode::SERVICE_UNAVAILABLE
+                        } else {
+                            StatusCode::OK
+                        };
+                        Ok::<_, hyper::Error>(
+                            Response::builder()
+                                .status(status)
+                                .body(Body::empty())
+                                .unwrap(),
+                        )
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server.map(|res| res.unwrap()));
+        (addr, received)
+    }
+
+    fn client(addr: SocketAddr) -> PushClient {
+        PushClient::new(&format!("http://{}/", addr), "batch", test_sources())
+            .grouping("instance", "host:1")
+            .backoff(Duration::from_millis(1), Duration::from_millis(5))
+    }
+
+    #[tokio::test]
+    async fn test_push_methods() {
+        let (addr, received) = gateway(0);
+        let client = client(addr).grouping("path", "/var/tmp");
+        client.push().await.unwrap();
+        client.push_add().await.unwrap();
+        client.delete().await.unwrap();
+
+        let received = received.lock().unwrap();
+        let path = "/metrics/job/batch/instance/host%3A1/path@base64/L3Zhci90bXA=";
+        assert_eq!(received.len(), 3);
+        assert_eq!(
+            (received[0].0.as_str(), received[0].1.as_str()),
+            ("PUT", path)
+        );
+        assert!(received[0].2.contains("test_requests 5"));
+        assert_eq!(
+            (received[1].0.as_str(), received[1].1.as_str()),
+            ("POST", path)
+        );
+        assert_eq!(
+            received[2],
+            ("DELETE".to_string(), path.to_string(), "".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let (addr, received) = gateway(2);
+        client(addr).retries(2).push().await.unwrap();
+        assert_eq!(received.lock().unwrap().len(), 3);
+
+        let (addr, received) = gateway(2);
+        match client(addr).retries(1).push().await {
+            Err(PushError::Status { status, .. }) => {
+                assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE)
+            }
+            res => panic!("unexpected push result {:?}", res),
+        }
+        assert_eq!(received.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_push_periodically() {
+        let (addr, received) = gateway(1);
+        let (tx, rx) = oneshot::channel::<()>();
+        let errors = Arc::new(Mutex::new(0));
+        let counted = errors.clone();
+        let pushing = tokio::spawn(client(addr).retries(0).push_periodically(
+            Duration::from_millis(10),
+            rx.map(|_| ()),
+            move |_| *counted.lock().unwrap() += 1,
+        ));
+
+        while received.lock().unwrap().len() < 3 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        tx.send(()).unwrap();
+        pushing.await.unwrap();
+
+        // the first push failed, the last one happened after shutdown
+        assert_eq!(*errors.lock().unwrap(), 1);
+        let received = received.lock().unwrap();
+        assert!(received.len() >= 4);
+        assert!(received.iter().all(|r| r.0 == "PUT"));
+    }
+
+    #[test]
+    fn test_job_encoding() {
+        let uri = |job: &str| {
+            PushClient::new("http://gateway:9091/", job, test_sources())
+                .grouping("instance", "")
+                .uri()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(
+            uri("batch"),
+            "http://gateway:9091/metrics/job/batch/instance@base64/="
+        );
+        assert_eq!(
+            uri("nightly/backup"),
+            "http://gateway:9091/metrics/job@base64/bmlnaHRseS9iYWNrdXA=/instance@base64/="
+        );
+        assert_eq!(
+            uri(""),
+            "http://gateway:9091/metrics/job@base64/=/instance@base64/="
+        );
+    }
+
+    #[test]
+    fn test_base64_url() {
+        assert_eq!(base64_url(b""), "=");
+        assert_eq!(base64_url(b"/var/tmp"), "L3Zhci90bXA=");
+        assert_eq!(base64_url(b"a"), "YQ==");
+        assert_eq!(base64_url(b"abc"), "YWJj");
+        assert_eq!(base64_url(&[0xfb, 0xff]), "-_8=");
+    }
+}
@@ -1,72 +1,546 @@
-use crate::json_encoder::JsonEncoder;
-use crate::never::Never;
-use futures::{future, Future, IntoFuture};
-use hyper::{service::Service, Body, Method, Request, Response, Server, StatusCode};
-use prometheus::{Encoder, TextEncoder};
-use std::net::SocketAddr;
+use crate::access::{AccessControl, Cidr};
+use crate::error::ServerError;
+use crate::handler::{HtmlRoute, MetricHandler, Reply, RequestInfo, Route};
+use crate::health::HealthChecks;
+use crate::json_encoder::NamingScheme;
+use crate::source::MetricSources;
+use futures::{Future, TryFutureExt};
+use hyper::{
+    header::{HeaderMap, HeaderName, ACCEPT, ACCEPT_ENCODING, AUTHORIZATION},
+    server::conn::AddrStream,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server,
+};
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
 
-struct MetricServer {
-    path_for_prom: String,
-    path_for_http: String,
+/// Configuration of the metric server, e.g.
+///
+/// ```ignore
+/// let builder = MetricServerBuilder::new(addr, MetricSources::global())
+///     .path_for_influx("/influx")
+///     .allow("10.0.0.0/8".parse()?)
+///     .basic_auth("prometheus", &hash_password("secret"));
+/// let (addr, server) = start_metric_server(builder, shutdown)?;
+/// ```
+pub struct MetricServerBuilder {
+    addr: SocketAddr,
+    pub(crate) path_for_prom: String,
+    pub(crate) path_for_http: String,
+    pub(crate) path_for_influx: Option<String>,
+    pub(crate) path_for_html: Option<HtmlRoute>,
+    pub(crate) json_naming: NamingScheme,
+    pub(crate) sources: MetricSources,
+    pub(crate) health: Option<HealthChecks>,
+    pub(crate) health_public: bool,
+    pub(crate) compress_min_size: Option<usize>,
+    pub(crate) access: AccessControl,
 }
 
-impl MetricServer {
-    pub fn new(path_for_prom: String, path_for_http: String) -> Self {
-        MetricServer {
-            path_for_prom,
-            path_for_http,
+impl MetricServerBuilder {
+    /// `addr` may use port 0 to pick a free port, the prometheus text format is
+    /// served on `/metrics` and json on `/json`
+    pub fn new(addr: SocketAddr, sources: MetricSources) -> Self {
+        MetricServerBuilder {
+            addr,
+            path_for_prom: "/metrics".to_string(),
+            path_for_http: "/json".to_string(),
+            path_for_influx: None,
+            path_for_html: None,
+            json_naming: NamingScheme::default(),
+            sources,
+            health: None,
+            health_public: false,
+            compress_min_size: None,
+            access: AccessControl::default(),
         }
     }
+
+    pub fn path_for_prom<S: Into<String>>(mut self, path: S) -> Self {
+        self.path_for_prom = path.into();
+        self
+    }
+
+    pub fn path_for_http<S: Into<String>>(mut self, path: S) -> Self {
+        self.path_for_http = path.into();
+        self
+    }
+
+    /// serve InfluxDB line protocol, e.g. for Telegraf
+    pub fn path_for_influx<S: Into<String>>(mut self, path: S) -> Self {
+        self.path_for_influx = Some(path.into());
+        self
+    }
+
+    /// serve an HTML page listing the metrics for reading in a browser, which
+    /// reloads itself every `refresh` seconds, 0 disables the reloads
+    pub fn path_for_html<S: Into<String>>(mut self, path: S, refresh: u32) -> Self {
+        self.path_for_html = Some(HtmlRoute {
+            path: path.into(),
+            refresh,
+        });
+        self
+    }
+
+    /// how metric names and labels are flattened into the keys of the json
+    /// served on `path_for_http`
+    pub fn json_naming(mut self, naming: NamingScheme) -> Self {
+        self.json_naming = naming;
+        self
+    }
+
+    /// add the liveness and readiness routes, the results of the checks are
+    /// exported along the other metrics
+    pub fn health(mut self, health: HealthChecks) -> Self {
+        self.health = Some(health);
+        self
+    }
+
+    /// answer the health routes also to peers denied by `allow`, `basic_auth`
+    /// or `hide_denied`, e.g. for the probes of the kubelet which come from
+    /// the node without credentials. Those peers only get the overall status,
+    /// not the checks and their errors.
+    pub fn health_public(mut self, public: bool) -> Self {
+        self.health_public = public;
+        self
+    }
+
+    /// compress responses of at least `min_size` bytes with gzip or deflate
+    /// when the client accepts it
+    pub fn compress(mut self, min_size: usize) -> Self {
+        self.compress_min_size = Some(min_size);
+        self
+    }
+
+    /// only answer peers in one of the allowed ranges, others get 403
+    pub fn allow(mut self, cidr: Cidr) -> Self {
+        self.access.allow(cidr);
+        self
+    }
+
+    /// require HTTP basic auth with one of the added users, `password_hash` is
+    /// the salted PBKDF2 hash of the password as returned by `hash_password`.
+    /// Requests without valid credentials get 401. Credentials are hashed
+    /// once and then remembered, while two unknown ones are being hashed
+    /// further ones get 429. Panics if `password_hash` isn't in that format.
+    pub fn basic_auth(mut self, user: &str, password_hash: &str) -> Self {
+        self.access.credential(user, password_hash);
+        self
+    }
+
+    /// answer denied requests with 404 instead of 401 or 403, so a scanner
+    /// can't tell that the server has any routes
+    pub fn hide_denied(mut self, hide: bool) -> Self {
+        self.access.hide(hide);
+        self
+    }
+
+    pub(crate) fn build(self) -> (SocketAddr, MetricHandler) {
+        (self.addr, MetricHandler::new(self))
+    }
 }
 
-impl Service for MetricServer {
-    type ReqBody = Body;
-    type ResBody = Body;
-    type Error = Never;
-    type Future = future::FutureResult<Response<Body>, Never>;
-
-    fn call(&mut self, req: Request<Self::ReqBody>) -> Self::Future {
-        let mut resp = Response::new(Body::empty());
-        if req.method() == &Method::GET {
-            let path = req.uri().path();
-            if path == self.path_for_prom {
-                *resp.body_mut() = Body::from(encode_metrics(TextEncoder::new()));
-            } else if path == self.path_for_http {
-                *resp.body_mut() = Body::from(encode_metrics(JsonEncoder));
-            } else {
-                *resp.status_mut() = StatusCode::NOT_FOUND;
-            }
-        } else {
-            *resp.status_mut() = StatusCode::NOT_FOUND;
-        }
+struct MetricServer {
+    handler: MetricHandler,
+}
 
-        future::ok(resp)
+impl MetricServer {
+    pub fn new(handler: MetricHandler) -> Self {
+        MetricServer { handler }
+    }
+
+    async fn call(&self, peer: IpAddr, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+        let headers = req.headers();
+        let info = RequestInfo {
+            peer,
+            authorization: headers.get(AUTHORIZATION).and_then(|v| v.to_str().ok()),
+            is_get: req.method() == Method::GET,
+            path: req.uri().path(),
+            query: req.uri().query().unwrap_or(""),
+            accept: header_values(headers, ACCEPT),
+            accept_encoding: header_values(headers, ACCEPT_ENCODING),
+        };
+        let reply = match self.handler.route(&info) {
+            Route::Reply(reply) => reply,
+            Route::Health { kind, detailed } => self.handler.health_reply(kind, detailed).await,
+        };
+        Ok(into_response(reply))
     }
 }
 
-impl IntoFuture for MetricServer {
-    type Future = future::FutureResult<Self::Item, Never>;
-    type Item = Self;
-    type Error = Never;
+fn header_values(headers: &HeaderMap, name: HeaderName) -> Vec<&str> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .collect()
+}
 
-    fn into_future(self) -> Self::Future {
-        future::ok(self)
+fn into_response(reply: Reply) -> Response<Body> {
+    let mut builder = Response::builder().status(reply.status);
+    for (name, value) in reply.headers {
+        builder = builder.header(name, value);
     }
+    builder.body(Body::from(reply.body)).unwrap()
 }
 
-fn encode_metrics(encoder: impl Encoder) -> Vec<u8> {
-    let metric_families = prometheus::gather();
-    let mut buffer = vec![];
-    encoder.encode(&metric_families, &mut buffer).unwrap();
-    buffer
+/// Bind the metric server configured by `builder`, it must be called within a
+/// tokio runtime.
+///
+/// Return the bound address and a future which serves scrapes until
+/// `shutdown` resolves, then stops accepting connections and resolves once
+/// in-flight scrapes are finished.
+pub fn start_metric_server<S>(
+    builder: MetricServerBuilder,
+    shutdown: S,
+) -> Result<(SocketAddr, impl Future<Output = Result<(), ServerError>>), ServerError>
+where
+    S: Future<Output = ()> + Send + 'static,
+{
+    let (addr, handler) = builder.build();
+    let server = Arc::new(MetricServer::new(handler));
+    let make_service = make_service_fn(move |conn: &AddrStream| {
+        let server = server.clone();
+        let peer = conn.remote_addr().ip();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let server = server.clone();
+                async move { server.call(peer, req).await }
+            }))
+        }
+    });
+    let srv = Server::try_bind(&addr)
+        .map_err(|e| ServerError::Bind {
+            addr,
+            source: Box::new(e),
+        })?
+        .serve(make_service);
+    let local_addr = srv.local_addr();
+    let srv = srv
+        .with_graceful_shutdown(shutdown)
+        .map_err(|e| ServerError::Serve(Box::new(e)));
+    Ok((local_addr, srv))
 }
 
-pub fn start_metric_server(
-    addr: SocketAddr,
-    path_for_prom: String,
-    path_for_http: String,
-) -> impl Future<Item = (), Error = ()> {
-    let srv = Server::try_bind(&addr).unwrap();
-    srv.serve(move || MetricServer::new(path_for_prom.clone(), path_for_http.clone()))
-        .map_err(|e| println!("server error: {}", e))
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{start_metric_server, MetricServerBuilder};
+    use crate::access::hash_password_with_rounds;
+    use crate::error::ServerError;
+    use crate::health::{CheckKind, HealthChecks};
+    use crate::json_encoder::NamingScheme;
+    use crate::openmetrics::OPENMETRICS_FORMAT;
+    use crate::source::{MetricSources, SourceOptions};
+    use flate2::read::GzDecoder;
+    use futures::{channel::oneshot, future, FutureExt};
+    use hyper::{
+        body,
+        header::{
+            ACCEPT, ACCEPT_ENCODING, AUTHORIZATION, CONTENT_ENCODING, CONTENT_TYPE, VARY,
+            WWW_AUTHENTICATE,
+        },
+        Body, Client, HeaderMap, Request, StatusCode,
+    };
+    use prometheus::{Counter, IntCounterVec, Opts, Registry};
+    use std::net::{SocketAddr, TcpListener};
+    use std::{io::Read, time::Duration};
+
+    pub fn test_sources() -> MetricSources {
+        let registry = Registry::new();
+        let counter = Counter::new("test_requests", "test counter").unwrap();
+        counter.inc_by(5.0);
+        registry.register(Box::new(counter)).unwrap();
+        MetricSources::new().registry(registry, SourceOptions::new())
+    }
+
+    async fn send_raw(req: Request<Body>) -> (StatusCode, HeaderMap, Vec<u8>) {
+        let resp = Client::new().request(req).await.unwrap();
+        let (parts, body) = resp.into_parts();
+        let body = body::to_bytes(body).await.unwrap();
+        (parts.status, parts.headers, body.to_vec())
+    }
+
+    pub async fn send(req: Request<Body>) -> (StatusCode, HeaderMap, String) {
+        let (status, headers, body) = send_raw(req).await;
+        (status, headers, String::from_utf8(body).unwrap())
+    }
+
+    pub async fn get(url: String) -> (StatusCode, String) {
+        let (status, _, body) = send(Request::get(url).body(Body::empty()).unwrap()).await;
+        (status, body)
+    }
+
+    pub fn builder(sources: MetricSources) -> MetricServerBuilder {
+        MetricServerBuilder::new("127.0.0.1:0".parse().unwrap(), sources)
+            .path_for_influx("/influx")
+            .path_for_html("/status", 10)
+            .compress(0)
+    }
+
+    pub fn serve(builder: MetricServerBuilder) -> (SocketAddr, oneshot::Sender<()>) {
+        let (tx, rx) = oneshot::channel::<()>();
+        let (addr, server) = start_metric_server(builder, rx.map(|_| ())).unwrap();
+        tokio::spawn(server.map(|res| res.unwrap()));
+        (addr, tx)
+    }
+
+    #[tokio::test]
+    async fn test_serve_and_shutdown() {
+        let (tx, rx) = oneshot::channel::<()>();
+        let builder = MetricServerBuilder::new("127.0.0.1:0".parse().unwrap(), test_sources())
+            .path_for_influx("/influx");
+        let (addr, server) = start_metric_server(builder, rx.map(|_| ())).unwrap();
+        assert_ne!(addr.port(), 0);
+        let server = tokio::spawn(server);
+
+        let (status, body) = get(format!("http://{}/metrics", addr)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("test_requests 5"));
+        let (status, body) = get(format!("http://{}/json", addr)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, r#"{"test_requests":5.0}"#);
+        let (status, body) = get(format!("http://{}/json?format=structured", addr)).await;
+        assert_eq!(status, StatusCode::OK);
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json[0]["name"], "test_requests");
+        assert_eq!(json[0]["help"], "test counter");
+        let (status, body) = get(format!("http://{}/json?prefix=other_", addr)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "{}");
+        let (status, body) = get(format!("http://{}/influx", addr)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "test_requests counter=5\n");
+        let (status, _) = get(format!("http://{}/unknown", addr)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = get(format!("http://{}/status", addr)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let req = Request::post(format!("http://{}/metrics", addr))
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(send(req).await.0, StatusCode::NOT_FOUND);
+
+        tx.send(()).unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_content_negotiation() {
+        let (addr, _shutdown) = serve(builder(test_sources()));
+        let scrape = |accept: &'static str| {
+            let req = Request::get(format!("http://{}/metrics", addr))
+                .header(ACCEPT, accept)
+                .body(Body::empty())
+                .unwrap();
+            async move {
+                let (status, headers, body) = send(req).await;
+                assert_eq!(status, StatusCode::OK);
+                (headers[CONTENT_TYPE].to_str().unwrap().to_string(), body)
+            }
+        };
+
+        let (content_type, body) = scrape("*/*").await;
+        assert_eq!(content_type, "text/plain; version=0.0.4");
+        assert!(!body.contains("# EOF"));
+
+        let (content_type, body) =
+            scrape("application/openmetrics-text;version=1.0.0,text/plain;version=0.0.4;q=0.5")
+                .await;
+        assert_eq!(content_type, OPENMETRICS_FORMAT);
+        assert!(body.contains("test_requests_total 5.0\n"));
+        assert!(body.ends_with("# EOF\n"));
+
+        let (content_type, _) = scrape("application/openmetrics-text;version=0.0.1").await;
+        assert_eq!(content_type, "text/plain; version=0.0.4");
+
+        let (_, headers, _) = send(
+            Request::get(format!("http://{}/json", addr))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(headers[CONTENT_TYPE], "application/json");
+
+        let (status, headers, body) = send(
+            Request::get(format!("http://{}/status?prefix=test_", addr))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[CONTENT_TYPE], "text/html; charset=utf-8");
+        assert!(body.contains("<h2>test_requests <span class=\"type\">counter</span></h2>"));
+    }
+
+    #[tokio::test]
+    async fn test_json_naming() {
+        let registry = Registry::new();
+        let counter =
+            IntCounterVec::new(Opts::new("requests", "test"), &["method", "status"]).unwrap();
+        counter.with_label_values(&["a.b", "c"]).inc();
+        counter.with_label_values(&["a", "b.c"]).inc_by(2);
+        counter.with_label_values(&["GET", "200"]).inc_by(3);
+        registry.register(Box::new(counter)).unwrap();
+        let sources = || MetricSources::new().registry(registry.clone(), SourceOptions::new());
+
+        let (addr, _shutdown) = serve(builder(sources()));
+        let (status, body) = get(format!("http://{}/json", addr)).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(body.contains("duplicate json key requests.a.b.c"));
+
+        let naming = NamingScheme::new().label_names(true);
+        let (addr, _shutdown) = serve(builder(sources()).json_naming(naming));
+        let (_, body) = get(format!("http://{}/json", addr)).await;
+        assert!(body.contains(r#""requests.method.a.b.status.c":1.0"#));
+        assert!(body.contains(r#""requests.method.a.status.b.c":2.0"#));
+    }
+
+    #[tokio::test]
+    async fn test_compression() {
+        let (addr, _shutdown) = serve(builder(test_sources()));
+        let (_, plain) = get(format!("http://{}/metrics", addr)).await;
+
+        let req = Request::get(format!("http://{}/metrics", addr))
+            .header(ACCEPT_ENCODING, "gzip")
+            .body(Body::empty())
+            .unwrap();
+        let (_, headers, body) = send_raw(req).await;
+        assert_eq!(headers[CONTENT_ENCODING], "gzip");
+        assert_eq!(headers[VARY], "Accept-Encoding");
+        let mut decoded = String::new();
+        GzDecoder::new(&body[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, plain);
+    }
+
+    #[tokio::test]
+    async fn test_health_routes() {
+        let timeout = Duration::from_millis(100);
+        let health = HealthChecks::new()
+            .readiness_path("/ready")
+            .check(CheckKind::Liveness, "loop", timeout, true, || Ok(()))
+            .check(CheckKind::Readiness, "db", timeout, true, || {
+                Err("down".to_string())
+            });
+        let (addr, _shutdown) = serve(builder(test_sources()).health(health));
+
+        let (status, body) = get(format!("http://{}/healthz", addr)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.starts_with(r#"{"checks":[{"critical":true,"#));
+        assert!(body.ends_with(r#""status":"ok"}"#));
+        let (status, body) = get(format!("http://{}/ready", addr)).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body.ends_with(r#""status":"fail"}"#));
+        let (status, _) = get(format!("http://{}/readyz", addr)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (_, body) = get(format!("http://{}/metrics", addr)).await;
+        assert!(
+            body.contains(r#"health_check_status{check="db",critical="true",kind="readiness"} 0"#)
+        );
+        assert!(
+            body.contains(r#"health_check_status{check="loop",critical="true",kind="liveness"} 1"#)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bind_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr: SocketAddr = listener.local_addr().unwrap();
+        let builder = MetricServerBuilder::new(addr, test_sources());
+        match start_metric_server(builder, future::pending::<()>()) {
+            Err(ServerError::Bind {
+                addr: bind_addr, ..
+            }) => assert_eq!(bind_addr, addr),
+            _ => panic!("bind to used port should fail"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_access_control() {
+        let (addr, _shutdown) = serve(
+            builder(test_sources())
+                .allow("127.0.0.0/8".parse().unwrap())
+                .basic_auth("prom", &hash_password_with_rounds("secret", 1000)),
+        );
+        let scrape = |authorization: Option<&'static str>| {
+            let mut req = Request::get(format!("http://{}/metrics", addr));
+            if let Some(authorization) = authorization {
+                req = req.header(AUTHORIZATION, authorization);
+            }
+            send(req.body(Body::empty()).unwrap())
+        };
+
+        let (status, _, body) = scrape(Some("Basic cHJvbTpzZWNyZXQ=")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("test_requests 5"));
+        let (status, headers, _) = scrape(None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(headers[WWW_AUTHENTICATE], r#"Basic realm="metrics""#);
+        let (status, _, _) = scrape(Some("Basic cHJvbTp3cm9uZw==")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (addr, _shutdown) = serve(
+            builder(test_sources())
+                .allow("10.0.0.0/8".parse().unwrap())
+                .hide_denied(true),
+        );
+        let (status, headers, _) = send(
+            Request::get(format!("http://{}/metrics", addr))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(headers.get(WWW_AUTHENTICATE).is_none());
+
+        let (addr, _shutdown) = serve(builder(test_sources()).allow("10.0.0.0/8".parse().unwrap()));
+        assert_eq!(
+            get(format!("http://{}/metrics", addr)).await.0,
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[tokio::test]
+    async fn test_public_health_routes() {
+        let health = || {
+            let timeout = Duration::from_millis(100);
+            HealthChecks::new()
+                .check(CheckKind::Liveness, "loop", timeout, true, || Ok(()))
+                .check(CheckKind::Readiness, "db", timeout, true, || {
+                    Err("connect to db:5432 failed".to_string())
+                })
+        };
+        let builder = || {
+            builder(test_sources())
+                .allow("127.0.0.0/8".parse().unwrap())
+                .basic_auth("prom", &hash_password_with_rounds("secret", 1000))
+                .hide_denied(true)
+        };
+        let probe = |addr: SocketAddr, path: &str, authorization: Option<&'static str>| {
+            let mut req = Request::get(format!("http://{}{}", addr, path));
+            if let Some(authorization) = authorization {
+                req = req.header(AUTHORIZATION, authorization);
+            }
+            send(req.body(Body::empty()).unwrap())
+        };
+
+        let (addr, _shutdown) = serve(builder().health(health()));
+        assert_eq!(probe(addr, "/healthz", None).await.0, StatusCode::NOT_FOUND);
+
+        let (addr, _shutdown) = serve(builder().health(health()).health_public(true));
+        let (status, _, body) = probe(addr, "/healthz", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, r#"{"status":"ok"}"#);
+        let (status, _, body) = probe(addr, "/readyz", None).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body, r#"{"status":"fail"}"#);
+        let (status, _, body) = probe(addr, "/readyz", Some("Basic cHJvbTpzZWNyZXQ=")).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body.contains("connect to db:5432 failed"));
+        assert_eq!(probe(addr, "/metrics", None).await.0, StatusCode::NOT_FOUND);
+    }
 }
@@ -0,0 +1,301 @@
+use crate::json_encoder::JsonEncoder;
+use crate::source::MetricSources;
+use prometheus::{Encoder, TextEncoder};
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::mpsc::{self, RecvTimeoutError, Sender},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone)]
+pub struct SnapshotOptions {
+    interval: Duration,
+    format: SnapshotFormat,
+    history: usize,
+}
+
+impl Default for SnapshotOptions {
+    fn default() -> Self {
+        SnapshotOptions {
+            interval: Duration::from_secs(10),
+            format: SnapshotFormat::Text,
+            history: 5,
+        }
+    }
+}
+
+impl SnapshotOptions {
+    pub fn new() -> Self {
+        SnapshotOptions::default()
+    }
+
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn format(mut self, format: SnapshotFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// number of previous snapshots kept as `<path>.1` (the newest) up to
+    /// `<path>.<history>`, 0 keeps only the current one
+    pub fn history(mut self, history: usize) -> Self {
+        self.history = history;
+        self
+    }
+}
+
+struct Dumper {
+    path: PathBuf,
+    sources: MetricSources,
+    options: SnapshotOptions,
+}
+
+impl Dumper {
+    fn dump(&self) -> io::Result<()> {
+        let metric_families = self.sources.gather();
+        let mut buffer = vec![];
+        let encoded = match self.options.format {
+            SnapshotFormat::Text => TextEncoder::new().encode(&metric_families, &mut buffer),
+            SnapshotFormat::Json => JsonEncoder::default().encode(&metric_families, &mut buffer),
+        };
+        encoded.map_err(|e| io::Error::other(e.to_string()))?;
+
+        // a reader never sees a partly written snapshot, the temp file is in
+        // the same directory so the rename doesn't cross file systems
+        let tmp = with_suffix(&self.path, "tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&buffer)?;
+        file.sync_all()?;
+        // the new snapshot goes in place even if the history couldn't be kept
+        let rotated = self.rotate();
+        fs::rename(&tmp, &self.path)?;
+        rotated
+    }
+
+    // shift `<path>.i` to `<path>.i+1` and link the current snapshot as
+    // `<path>.1`, so `<path>` itself stays in place until it is replaced
+    fn rotate(&self) -> io::Result<()> {
+        let history = self.options.history;
+        if history == 0 || !self.path.exists() {
+            return Ok(());
+        }
+        for i in (1..history).rev() {
+            let from = with_suffix(&self.path, &i.to_string());
+            if from.exists() {
+                fs::rename(&from, with_suffix(&self.path, &(i + 1).to_string()))?;
+            }
+        }
+        let newest = with_suffix(&self.path, "1");
+        if newest.exists() {
+            fs::remove_file(&newest)?;
+        }
+        fs::hard_link(&self.path, &newest).or_else(|_| fs::copy(&self.path, &newest).map(|_| ()))
+    }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Stops the dumping thread and writes a last snapshot when dropped
+pub struct SnapshotHandle {
+    stop_sender: Sender<()>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for SnapshotHandle {
+    fn drop(&mut self) {
+        let _ = self.stop_sender.send(());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Write the gathered metrics to `path` every interval, for a look at the last
+/// known values after the process crashed.
+///
+/// The first snapshot is written before returning so a bad path is reported
+/// right away, later failures are passed to `on_error` and retried with the
+/// next interval.
+pub fn start_snapshot_dumper<P, F>(
+    path: P,
+    sources: MetricSources,
+    options: SnapshotOptions,
+    on_error: F,
+) -> io::Result<SnapshotHandle>
+where
+    P: Into<PathBuf>,
+    F: Fn(io::Error) + Send + 'static,
+{
+    let dumper = Dumper {
+        path: path.into(),
+        sources,
+        options,
+    };
+    dumper.dump()?;
+
+    let (stop_sender, stop_receiver) = mpsc::channel();
+    let interval = dumper.options.interval;
+    let thread = thread::spawn(move || loop {
+        let stop = stop_receiver.recv_timeout(interval);
+        if let Err(e) = dumper.dump() {
+            on_error(e);
+        }
+        if stop != Err(RecvTimeoutError::Timeout) {
+            return;
+        }
+    });
+    Ok(SnapshotHandle {
+        stop_sender,
+        thread: Some(thread),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{start_snapshot_dumper, with_suffix, Dumper, SnapshotFormat, SnapshotOptions};
+    use crate::source::{MetricSources, SourceOptions};
+    use prometheus::{IntCounter, Registry};
+    use std::{fs, sync::mpsc, time::Duration};
+
+    fn ignore(_: std::io::Error) {}
+
+    #[test]
+    fn test_dump_and_rotate() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("metrics.json");
+        let registry = Registry::new();
+        let counter = IntCounter::new("requests", "test").unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        let sources = || MetricSources::new().registry(registry.clone(), SourceOptions::new());
+
+        let options = SnapshotOptions::new()
+            .interval(Duration::from_secs(3600))
+            .format(SnapshotFormat::Json)
+            .history(2);
+        let handle = start_snapshot_dumper(&path, sources(), options.clone(), ignore).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), r#"{"requests":0.0}"#);
+        counter.inc();
+        // dropping writes the final snapshot
+        drop(handle);
+        assert_eq!(fs::read_to_string(&path).unwrap(), r#"{"requests":1.0}"#);
+        let previous = with_suffix(&path, "1");
+        assert_eq!(
+            fs::read_to_string(&previous).unwrap(),
+            r#"{"requests":0.0}"#
+        );
+
+        for _ in 0..3 {
+            counter.inc();
+            drop(start_snapshot_dumper(&path, sources(), options.clone(), ignore).unwrap());
+        }
+        let mut files = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(
+            files,
+            vec!["metrics.json", "metrics.json.1", "metrics.json.2"]
+        );
+        assert_eq!(fs::read_to_string(&path).unwrap(), r#"{"requests":4.0}"#);
+        assert_eq!(
+            fs::read_to_string(with_suffix(&path, "2")).unwrap(),
+            r#"{"requests":3.0}"#
+        );
+    }
+
+    #[test]
+    fn test_periodic_text_dump() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("metrics.prom");
+        let registry = Registry::new();
+        let counter = IntCounter::new("requests", "test").unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        let sources = MetricSources::new().registry(registry, SourceOptions::new());
+
+        let options = SnapshotOptions::new()
+            .interval(Duration::from_millis(10))
+            .history(0);
+        let _handle = start_snapshot_dumper(&path, sources, options, ignore).unwrap();
+        counter.inc_by(7);
+        let mut dumped = String::new();
+        for _ in 0..200 {
+            dumped = fs::read_to_string(&path).unwrap();
+            if dumped.contains("requests 7") {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert!(dumped.contains("requests 7"));
+        assert!(!with_suffix(&path, "1").exists());
+        assert!(!with_suffix(&path, "tmp").exists());
+    }
+
+    #[test]
+    fn test_bad_path() {
+        let sources = MetricSources::new();
+        assert!(start_snapshot_dumper(
+            "/nonexistent/metrics",
+            sources,
+            SnapshotOptions::new(),
+            ignore
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_report_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("metrics.prom");
+        let options = SnapshotOptions::new()
+            .interval(Duration::from_millis(10))
+            .history(0);
+        let (errors, failed) = mpsc::channel();
+        let _handle = start_snapshot_dumper(&path, MetricSources::new(), options, move |e| {
+            let _ = errors.send(e);
+        })
+        .unwrap();
+        fs::remove_dir_all(dir.path()).unwrap();
+        assert!(failed.recv_timeout(Duration::from_secs(5)).is_ok());
+    }
+
+    #[test]
+    fn test_rotate_error_keeps_new_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("metrics.prom");
+        fs::write(&path, "old").unwrap();
+        // `<path>.1` can't be replaced
+        fs::create_dir(with_suffix(&path, "1")).unwrap();
+        fs::write(with_suffix(&path, "1").join("file"), "").unwrap();
+
+        let registry = Registry::new();
+        let counter = IntCounter::new("requests", "test").unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        counter.inc();
+        let dumper = Dumper {
+            path: path.clone(),
+            sources: MetricSources::new().registry(registry, SourceOptions::new()),
+            options: SnapshotOptions::new().history(1),
+        };
+        assert!(dumper.dump().is_err());
+        assert!(fs::read_to_string(&path).unwrap().contains("requests 1"));
+        assert!(!with_suffix(&path, "tmp").exists());
+    }
+}
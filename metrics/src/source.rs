@@ -0,0 +1,419 @@
+use crate::openmetrics::Exemplars;
+use prometheus::{
+    core::Collector,
+    proto::{LabelPair, Metric, MetricFamily},
+    IntCounterVec, Opts, Registry,
+};
+use std::collections::{BTreeMap, HashSet};
+
+type GatherFn = Box<dyn Fn() -> Vec<MetricFamily> + Send + Sync>;
+
+// the label sets of a family's series, sorted by label name
+type LabelSet = Vec<(String, String)>;
+
+/// Prefix and constant labels applied to every family gathered from one source
+#[derive(Debug, Clone, Default)]
+pub struct SourceOptions {
+    prefix: Option<String>,
+    const_labels: Vec<(String, String)>,
+}
+
+impl SourceOptions {
+    pub fn new() -> Self {
+        SourceOptions::default()
+    }
+
+    /// family `name` is exposed as `prefix_name`
+    pub fn prefix<S: Into<String>>(mut self, prefix: S) -> Self {
+        self.prefix = Some(prefix.into());
+        self
+    }
+
+    /// label is added to every metric which doesn't already have it
+    pub fn const_label<S: Into<String>>(mut self, name: S, value: S) -> Self {
+        self.const_labels.push((name.into(), value.into()));
+        self
+    }
+}
+
+/// Select families by exact name and/or name prefix, an empty filter keeps
+/// every family
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MetricFilter {
+    names: Vec<String>,
+    prefixes: Vec<String>,
+}
+
+impl MetricFilter {
+    pub fn new() -> Self {
+        MetricFilter::default()
+    }
+
+    /// `?name[]=foo&name[]=bar&prefix=baz_`, other parameters are ignored
+    pub fn from_query(query: &str) -> Self {
+        let mut filter = MetricFilter::new();
+        for (k, v) in form_urlencoded::parse(query.as_bytes()) {
+            match k.as_ref() {
+                "name[]" | "name" => filter = filter.name(v),
+                "prefix" => filter = filter.prefix(v),
+                _ => {}
+            }
+        }
+        filter
+    }
+
+    pub fn name<S: Into<String>>(mut self, name: S) -> Self {
+        self.names.push(name.into());
+        self
+    }
+
+    pub fn prefix<S: Into<String>>(mut self, prefix: S) -> Self {
+        self.prefixes.push(prefix.into());
+        self
+    }
+
+    /// a family has to match one of the names, if any, and one of the
+    /// prefixes, if any
+    pub fn matches(&self, name: &str) -> bool {
+        (self.names.is_empty() || self.names.iter().any(|n| n == name))
+            && (self.prefixes.is_empty() || self.prefixes.iter().any(|p| name.starts_with(p)))
+    }
+}
+
+struct Source {
+    gather: GatherFn,
+    options: SourceOptions,
+}
+
+impl Source {
+    fn gather(&self, filter: &MetricFilter) -> Vec<MetricFamily> {
+        let mut families = (self.gather)();
+        if let Some(prefix) = &self.options.prefix {
+            for mf in &mut families {
+                let name = format!("{}_{}", prefix, mf.get_name());
+                mf.set_name(name);
+            }
+        }
+        families.retain(|mf| filter.matches(mf.get_name()));
+        for mf in &mut families {
+            if self.options.const_labels.is_empty() {
+                continue;
+            }
+            for m in mf.mut_metric().iter_mut() {
+                for (name, value) in &self.options.const_labels {
+                    if m.get_label().iter().any(|l| l.get_name() == name) {
+                        continue;
+                    }
+                    let mut label = LabelPair::new();
+                    label.set_name(name.clone());
+                    label.set_value(value.clone());
+                    m.mut_label().push(label);
+                }
+                m.mut_label().sort_by(|a, b| a.get_name().cmp(b.get_name()));
+            }
+        }
+        families
+    }
+}
+
+const DROPPED_SERIES: &str = "metrics_sources_dropped_series_total";
+
+/// MetricSources is the set of registries and collectors exposed by the
+/// metric server, families with the same name from different sources are
+/// merged into one.
+///
+/// Series which can't be merged are dropped, a series whose labels are the
+/// same as an earlier one's and the series of a family whose type conflicts
+/// with an earlier one. Every gather counts them in
+/// `metrics_sources_dropped_series_total{reason="duplicate"|"type_conflict"}`,
+/// which is added to the families once it isn't zero. Tell the series of
+/// same named families apart with `SourceOptions::const_label`.
+pub struct MetricSources {
+    sources: Vec<Source>,
+    exemplars: Option<Exemplars>,
+    dropped: IntCounterVec,
+}
+
+impl Default for MetricSources {
+    fn default() -> Self {
+        let dropped = IntCounterVec::new(
+            Opts::new(
+                DROPPED_SERIES,
+                "Series dropped while merging the metric sources.",
+            ),
+            &["reason"],
+        )
+        .unwrap();
+        MetricSources {
+            sources: vec![],
+            exemplars: None,
+            dropped,
+        }
+    }
+}
+
+impl MetricSources {
+    pub fn new() -> Self {
+        MetricSources::default()
+    }
+
+    /// sources only holding the process wide default registry
+    pub fn global() -> Self {
+        MetricSources::new().default_registry(SourceOptions::default())
+    }
+
+    pub fn default_registry(self, options: SourceOptions) -> Self {
+        self.provider(prometheus::gather, options)
+    }
+
+    pub fn registry(self, registry: Registry, options: SourceOptions) -> Self {
+        self.provider(move || registry.gather(), options)
+    }
+
+    pub fn collector(self, collector: Box<dyn Collector>, options: SourceOptions) -> Self {
+        self.provider(move || collector.collect(), options)
+    }
+
+    pub fn provider<F>(mut self, gather: F, options: SourceOptions) -> Self
+    where
+        F: Fn() -> Vec<MetricFamily> + Send + Sync + 'static,
+    {
+        self.sources.push(Source {
+            gather: Box::new(gather),
+            options,
+        });
+        self
+    }
+
+    /// exemplars exposed along the families in the OpenMetrics format
+    pub fn exemplars(mut self, exemplars: Exemplars) -> Self {
+        self.exemplars = Some(exemplars);
+        self
+    }
+
+    pub(crate) fn exemplar_store(&self) -> Option<&Exemplars> {
+        self.exemplars.as_ref()
+    }
+
+    /// families from all sources sorted by name
+    pub fn gather(&self) -> Vec<MetricFamily> {
+        self.gather_filtered(&MetricFilter::default())
+    }
+
+    /// families matching `filter`, other families are dropped before labels
+    /// are added and families are merged
+    pub fn gather_filtered(&self, filter: &MetricFilter) -> Vec<MetricFamily> {
+        let mut merged: BTreeMap<String, (MetricFamily, HashSet<LabelSet>)> = BTreeMap::new();
+        for source in &self.sources {
+            for mut mf in source.gather(filter) {
+                let metrics = mf.take_metric();
+                let field_type = mf.get_field_type();
+                let (family, seen) = merged
+                    .entry(mf.get_name().to_string())
+                    .or_insert_with(|| (mf, HashSet::new()));
+                if family.get_field_type() != field_type {
+                    self.drop_series("type_conflict", metrics.len());
+                    continue;
+                }
+                for m in metrics.into_iter() {
+                    if seen.insert(label_set(&m)) {
+                        family.mut_metric().push(m);
+                    } else {
+                        self.drop_series("duplicate", 1);
+                    }
+                }
+            }
+        }
+
+        let mut families: Vec<MetricFamily> = merged.into_values().map(|(mf, _)| mf).collect();
+        if filter.matches(DROPPED_SERIES) {
+            for mf in self.dropped.collect() {
+                if mf.get_metric().is_empty() {
+                    continue;
+                }
+                if let Err(at) = families.binary_search_by(|f| f.get_name().cmp(DROPPED_SERIES)) {
+                    families.insert(at, mf);
+                }
+            }
+        }
+        families
+    }
+
+    fn drop_series(&self, reason: &str, count: usize) {
+        self.dropped
+            .with_label_values(&[reason])
+            .inc_by(count as i64);
+    }
+}
+
+fn label_set(m: &Metric) -> LabelSet {
+    let mut labels: LabelSet = m
+        .get_label()
+        .iter()
+        .map(|l| (l.get_name().to_string(), l.get_value().to_string()))
+        .collect();
+    labels.sort();
+    labels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MetricFilter, MetricSources, SourceOptions};
+    use prometheus::{Counter, Gauge, IntCounterVec, Opts, Registry};
+
+    fn registry_with_counter(name: &str, value: f64) -> Registry {
+        let registry = Registry::new();
+        let counter = Counter::new(name, "test counter").unwrap();
+        counter.inc_by(value);
+        registry.register(Box::new(counter)).unwrap();
+        registry
+    }
+
+    #[test]
+    fn test_prefix_and_const_labels() {
+        let sources = MetricSources::new().registry(
+            registry_with_counter("requests", 3.0),
+            SourceOptions::new()
+                .prefix("dns")
+                .const_label("service", "resolver"),
+        );
+        let families = sources.gather();
+        assert_eq!(families.len(), 1);
+        assert_eq!(families[0].get_name(), "dns_requests");
+        let metric = &families[0].get_metric()[0];
+        assert_eq!(metric.get_counter().get_value(), 3.0);
+        assert_eq!(metric.get_label().len(), 1);
+        assert_eq!(metric.get_label()[0].get_name(), "service");
+        assert_eq!(metric.get_label()[0].get_value(), "resolver");
+    }
+
+    #[test]
+    fn test_const_label_keeps_metric_label() {
+        let registry = Registry::new();
+        let counter = IntCounterVec::new(Opts::new("requests", "test"), &["zone", "app"]).unwrap();
+        counter.with_label_values(&["cn", "web"]).inc();
+        registry.register(Box::new(counter)).unwrap();
+        let sources = MetricSources::new().registry(
+            registry,
+            SourceOptions::new()
+                .const_label("zone", "default")
+                .const_label("instance", "a"),
+        );
+        let families = sources.gather();
+        let labels = families[0].get_metric()[0]
+            .get_label()
+            .iter()
+            .map(|l| (l.get_name(), l.get_value()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            labels,
+            vec![("app", "web"), ("instance", "a"), ("zone", "cn")]
+        );
+    }
+
+    #[test]
+    fn test_merge_families() {
+        let a = registry_with_counter("requests", 1.0);
+        let b = registry_with_counter("requests", 2.0);
+        let c = Registry::new();
+        c.register(Box::new(Gauge::new("requests", "conflict").unwrap()))
+            .unwrap();
+        c.register(Box::new(Gauge::new("connections", "gauge").unwrap()))
+            .unwrap();
+        let sources = MetricSources::new()
+            .registry(a, SourceOptions::new().const_label("source", "a"))
+            .registry(b, SourceOptions::new().const_label("source", "b"))
+            .registry(c, SourceOptions::new());
+
+        let families = sources.gather();
+        let names = families.iter().map(|mf| mf.get_name()).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![
+                "connections",
+                "metrics_sources_dropped_series_total",
+                "requests"
+            ]
+        );
+        let dropped = &families[1].get_metric()[0];
+        assert_eq!(dropped.get_label()[0].get_value(), "type_conflict");
+        assert_eq!(dropped.get_counter().get_value(), 1.0);
+        let values = families[2]
+            .get_metric()
+            .iter()
+            .map(|m| m.get_counter().get_value())
+            .collect::<Vec<_>>();
+        assert_eq!(values, vec![1.0, 2.0]);
+    }
+
+    #[test]
+    fn test_drop_duplicate_series() {
+        let sources = MetricSources::new()
+            .registry(registry_with_counter("requests", 1.0), SourceOptions::new())
+            .registry(registry_with_counter("requests", 2.0), SourceOptions::new());
+        let families = sources.gather_filtered(&MetricFilter::new().name("requests"));
+        assert_eq!(families.len(), 1);
+        assert_eq!(families[0].get_metric().len(), 1);
+        assert_eq!(families[0].get_metric()[0].get_counter().get_value(), 1.0);
+
+        let families = sources.gather();
+        assert_eq!(
+            families[0].get_name(),
+            "metrics_sources_dropped_series_total"
+        );
+        let dropped = &families[0].get_metric()[0];
+        assert_eq!(dropped.get_label()[0].get_value(), "duplicate");
+        assert_eq!(dropped.get_counter().get_value(), 2.0);
+    }
+
+    #[test]
+    fn test_collector_source() {
+        let counter = Counter::new("jobs", "test").unwrap();
+        counter.inc();
+        let sources =
+            MetricSources::new().collector(Box::new(counter.clone()), SourceOptions::new());
+        counter.inc();
+        let families = sources.gather();
+        assert_eq!(families[0].get_metric()[0].get_counter().get_value(), 2.0);
+    }
+
+    #[test]
+    fn test_filter() {
+        let sources = MetricSources::new()
+            .registry(registry_with_counter("requests", 1.0), SourceOptions::new())
+            .registry(
+                registry_with_counter("requests", 2.0),
+                SourceOptions::new().prefix("dns"),
+            )
+            .registry(
+                registry_with_counter("dns_queries", 3.0),
+                SourceOptions::new(),
+            );
+        let names = |filter: MetricFilter| {
+            sources
+                .gather_filtered(&filter)
+                .iter()
+                .map(|mf| mf.get_name().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(names(MetricFilter::new()).len(), 3);
+        assert_eq!(
+            names(MetricFilter::from_query(
+                "name[]=requests&name[]=dns_queries"
+            )),
+            vec!["dns_queries", "requests"]
+        );
+        assert_eq!(
+            names(MetricFilter::from_query("prefix=dns_&format=structured")),
+            vec!["dns_queries", "dns_requests"]
+        );
+        assert_eq!(
+            names(MetricFilter::from_query(
+                "name%5B%5D=dns_requests&prefix=dns_"
+            )),
+            vec!["dns_requests"]
+        );
+        assert!(names(MetricFilter::from_query("name[]=requests&prefix=dns_")).is_empty());
+    }
+}
@@ -0,0 +1,262 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use rbtree::rbtree::RBTree;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+const SIZES: &[usize] = &[100, 1_000, 10_000];
+
+trait BenchKey: Ord + Hash + Clone {
+    const NAME: &'static str;
+    fn from_u64(n: u64) -> Self;
+}
+
+impl BenchKey for u64 {
+    const NAME: &'static str = "u64";
+    fn from_u64(n: u64) -> Self {
+        n
+    }
+}
+
+impl BenchKey for String {
+    const NAME: &'static str = "string";
+    fn from_u64(n: u64) -> Self {
+        format!("key-{:016x}", n)
+    }
+}
+
+// xorshift keeps key generation deterministic between runs
+fn random_numbers(size: usize, seed: u64) -> Vec<u64> {
+    let mut x = seed;
+    (0..size)
+        .map(|_| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x
+        })
+        .collect()
+}
+
+fn random_keys<K: BenchKey>(size: usize) -> Vec<K> {
+    random_numbers(size, 0x2545_F491_4F6C_DD1D)
+        .into_iter()
+        .map(K::from_u64)
+        .collect()
+}
+
+fn sequential_keys<K: BenchKey>(size: usize) -> Vec<K> {
+    (0..size as u64).map(K::from_u64).collect()
+}
+
+// keys which are never inserted, odd numbers are filled from the even ones
+fn interleaved_keys<K: BenchKey>(size: usize) -> (Vec<K>, Vec<K>) {
+    let numbers = random_numbers(size, 0x9E37_79B9_7F4A_7C15);
+    let present = numbers.iter().map(|n| K::from_u64(n & !1)).collect();
+    let absent = numbers.iter().map(|n| K::from_u64(n | 1)).collect();
+    (present, absent)
+}
+
+fn bench_insert<K: BenchKey>(c: &mut Criterion, scenario: &str, keys_fn: fn(usize) -> Vec<K>) {
+    let mut group = c.benchmark_group(format!("{}/{}", scenario, K::NAME));
+    for &size in SIZES {
+        let keys = keys_fn(size);
+        group.bench_with_input(BenchmarkId::new("RBTree", size), &keys, |b, keys| {
+            b.iter_batched(
+                || keys.clone(),
+                |keys| {
+                    let mut tree = RBTree::new();
+                    for k in keys {
+                        tree.insert(k, 0u64);
+                    }
+                    tree
+                },
+                BatchSize::LargeInput,
+            )
+        });
+        group.bench_with_input(BenchmarkId::new("BTreeMap", size), &keys, |b, keys| {
+            b.iter_batched(
+                || keys.clone(),
+                |keys| {
+                    let mut map = BTreeMap::new();
+                    for k in keys {
+                        map.insert(k, 0u64);
+                    }
+                    map
+                },
+                BatchSize::LargeInput,
+            )
+        });
+        group.bench_with_input(BenchmarkId::new("HashMap", size), &keys, |b, keys| {
+            b.iter_batched(
+                || keys.clone(),
+                |keys| {
+                    let mut map = HashMap::new();
+                    for k in keys {
+                        map.insert(k, 0u64);
+                    }
+                    map
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn bench_lookup<K: BenchKey>(c: &mut Criterion, hit: bool) {
+    let scenario = if hit { "lookup_hit" } else { "lookup_miss" };
+    let mut group = c.benchmark_group(format!("{}/{}", scenario, K::NAME));
+    for &size in SIZES {
+        let (present, absent) = interleaved_keys::<K>(size);
+        let lookups = if hit { present.clone() } else { absent };
+        let tree: RBTree<K, u64> = present.iter().cloned().map(|k| (k, 0)).collect();
+        let btree: BTreeMap<K, u64> = present.iter().cloned().map(|k| (k, 0)).collect();
+        let hash: HashMap<K, u64> = present.into_iter().map(|k| (k, 0)).collect();
+
+        group.bench_with_input(BenchmarkId::new("RBTree", size), &lookups, |b, keys| {
+            b.iter(|| keys.iter().filter(|k| tree.contains_key(k)).count())
+        });
+        group.bench_with_input(BenchmarkId::new("BTreeMap", size), &lookups, |b, keys| {
+            b.iter(|| keys.iter().filter(|k| btree.contains_key(k)).count())
+        });
+        group.bench_with_input(BenchmarkId::new("HashMap", size), &lookups, |b, keys| {
+            b.iter(|| keys.iter().filter(|k| hash.contains_key(k)).count())
+        });
+    }
+    group.finish();
+}
+
+fn bench_remove<K: BenchKey>(c: &mut Criterion) {
+    let mut group = c.benchmark_group(format!("remove/{}", K::NAME));
+    for &size in SIZES {
+        let keys = random_keys::<K>(size);
+        let tree: RBTree<K, u64> = keys.iter().cloned().map(|k| (k, 0)).collect();
+        let btree: BTreeMap<K, u64> = keys.iter().cloned().map(|k| (k, 0)).collect();
+        let hash: HashMap<K, u64> = keys.iter().cloned().map(|k| (k, 0)).collect();
+
+        group.bench_with_input(BenchmarkId::new("RBTree", size), &keys, |b, keys| {
+            b.iter_batched(
+                || tree.clone(),
+                |mut tree| {
+                    for k in keys {
+                        tree.remove(k);
+                    }
+                    tree
+                },
+                BatchSize::LargeInput,
+            )
+        });
+        group.bench_with_input(BenchmarkId::new("BTreeMap", size), &keys, |b, keys| {
+            b.iter_batched(
+                || btree.clone(),
+                |mut map| {
+                    for k in keys {
+                        map.remove(k);
+                    }
+                    map
+                },
+                BatchSize::LargeInput,
+            )
+        });
+        group.bench_with_input(BenchmarkId::new("HashMap", size), &keys, |b, keys| {
+            b.iter_batched(
+                || hash.clone(),
+                |mut map| {
+                    for k in keys {
+                        map.remove(k);
+                    }
+                    map
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn bench_iterate<K: BenchKey>(c: &mut Criterion) {
+    let mut group = c.benchmark_group(format!("iterate/{}", K::NAME));
+    for &size in SIZES {
+        let keys = random_keys::<K>(size);
+        let tree: RBTree<K, u64> = keys.iter().cloned().map(|k| (k, 1)).collect();
+        let btree: BTreeMap<K, u64> = keys.iter().cloned().map(|k| (k, 1)).collect();
+        let hash: HashMap<K, u64> = keys.into_iter().map(|k| (k, 1)).collect();
+
+        group.bench_function(BenchmarkId::new("RBTree", size), |b| {
+            b.iter(|| tree.values().sum::<u64>())
+        });
+        group.bench_function(BenchmarkId::new("BTreeMap", size), |b| {
+            b.iter(|| btree.values().sum::<u64>())
+        });
+        group.bench_function(BenchmarkId::new("HashMap", size), |b| {
+            b.iter(|| hash.values().sum::<u64>())
+        });
+    }
+    group.finish();
+}
+
+// HashMap has no ordered lookup, so only the ordered maps are compared
+fn bench_find_less_equal<K: BenchKey>(c: &mut Criterion) {
+    let mut group = c.benchmark_group(format!("find_less_equal/{}", K::NAME));
+    for &size in SIZES {
+        let (present, absent) = interleaved_keys::<K>(size);
+        let tree: RBTree<K, u64> = present.iter().cloned().map(|k| (k, 0)).collect();
+        let btree: BTreeMap<K, u64> = present.into_iter().map(|k| (k, 0)).collect();
+
+        group.bench_with_input(BenchmarkId::new("RBTree", size), &absent, |b, keys| {
+            b.iter(|| {
+                keys.iter()
+                    .filter(|k| !tree.find_less_equal(k).0.is_null())
+                    .count()
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("BTreeMap", size), &absent, |b, keys| {
+            b.iter(|| {
+                keys.iter()
+                    .filter(|k| btree.range(..=(*k).clone()).next_back().is_some())
+                    .count()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_clone<K: BenchKey>(c: &mut Criterion) {
+    let mut group = c.benchmark_group(format!("clone/{}", K::NAME));
+    for &size in SIZES {
+        let keys = random_keys::<K>(size);
+        let tree: RBTree<K, u64> = keys.iter().cloned().map(|k| (k, 0)).collect();
+        let btree: BTreeMap<K, u64> = keys.iter().cloned().map(|k| (k, 0)).collect();
+        let hash: HashMap<K, u64> = keys.into_iter().map(|k| (k, 0)).collect();
+
+        group.bench_function(BenchmarkId::new("RBTree", size), |b| {
+            b.iter(|| tree.clone())
+        });
+        group.bench_function(BenchmarkId::new("BTreeMap", size), |b| {
+            b.iter(|| btree.clone())
+        });
+        group.bench_function(BenchmarkId::new("HashMap", size), |b| {
+            b.iter(|| hash.clone())
+        });
+    }
+    group.finish();
+}
+
+fn bench_key_type<K: BenchKey>(c: &mut Criterion) {
+    bench_insert::<K>(c, "insert_random", random_keys);
+    bench_insert::<K>(c, "insert_sequential", sequential_keys);
+    bench_lookup::<K>(c, true);
+    bench_lookup::<K>(c, false);
+    bench_remove::<K>(c);
+    bench_iterate::<K>(c);
+    bench_find_less_equal::<K>(c);
+    bench_clone::<K>(c);
+}
+
+fn benches(c: &mut Criterion) {
+    bench_key_type::<u64>(c);
+    bench_key_type::<String>(c);
+}
+
+criterion_group!(rbtree_benches, benches);
+criterion_main!(rbtree_benches);
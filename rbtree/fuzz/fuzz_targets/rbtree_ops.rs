@@ -0,0 +1,24 @@
+// run with `cargo +nightly fuzz run rbtree_ops` from the rbtree directory
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rbtree::model::{apply, Op};
+use rbtree::rbtree::RBTree;
+use std::collections::BTreeMap;
+
+// up to 3 bytes from an alphabet of 4, so keys collide and share prefixes
+fn key(seed: u16) -> Vec<u8> {
+    let len = (seed % 4) as usize;
+    (0..len)
+        .map(|i| ((seed >> (2 + i * 2)) & 3) as u8)
+        .collect()
+}
+
+fuzz_target!(|ops: Vec<Op<u16>>| {
+    let mut tree = RBTree::new();
+    let mut model = BTreeMap::new();
+    for op in ops {
+        apply(&mut tree, &mut model, op.map_key(key));
+    }
+    assert!(tree.iter().eq(model.iter()));
+});
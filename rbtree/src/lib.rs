@@ -1,5 +1,17 @@
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+// only for the tests and the fuzz target
+#[cfg(any(test, feature = "model"))]
+#[doc(hidden)]
+pub mod model;
 pub mod rbnode;
 pub mod rbtree;
+#[cfg(feature = "std")]
+pub mod snapshot;
+#[cfg(feature = "std")]
+pub mod ttl_cache;
 
 #[cfg(test)]
 mod rbtree_test;
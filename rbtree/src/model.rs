@@ -0,0 +1,221 @@
+//! The operations and model check shared by the model based proptest and the
+//! `rbtree_ops` fuzz target.
+
+use crate::rbtree::RBTree;
+use alloc::{collections::BTreeMap, vec::Vec};
+
+/// One call on both an `RBTree` and the `BTreeMap` modelling it, keys are
+/// generic so the generators can pick small key spaces and map them to
+/// `Vec<u8>` afterwards
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum Op<K> {
+    Insert(K, u16),
+    Remove(K),
+    Get(K),
+    GetMut(K, u16),
+    PopFirst,
+    PopLast,
+    FirstLastMut(u16),
+    FindLessEqual(K),
+    PrefixIter(K),
+    LongestPrefixMatch(K),
+    Iter(Vec<bool>),
+    IterMut(Vec<bool>, u16),
+    IntoIter(Vec<bool>),
+    Clone,
+    Eq(K),
+    Extend(Vec<(K, u16)>),
+    Clear,
+}
+
+impl<K> Op<K> {
+    pub fn map_key<F: Fn(K) -> Vec<u8>>(self, f: F) -> Op<Vec<u8>> {
+        match self {
+            Op::Insert(k, v) => Op::Insert(f(k), v),
+            Op::Remove(k) => Op::Remove(f(k)),
+            Op::Get(k) => Op::Get(f(k)),
+            Op::GetMut(k, v) => Op::GetMut(f(k), v),
+            Op::PopFirst => Op::PopFirst,
+            Op::PopLast => Op::PopLast,
+            Op::FirstLastMut(v) => Op::FirstLastMut(v),
+            Op::FindLessEqual(k) => Op::FindLessEqual(f(k)),
+            Op::PrefixIter(k) => Op::PrefixIter(f(k)),
+            Op::LongestPrefixMatch(k) => Op::LongestPrefixMatch(f(k)),
+            Op::Iter(directions) => Op::Iter(directions),
+            Op::IterMut(directions, v) => Op::IterMut(directions, v),
+            Op::IntoIter(directions) => Op::IntoIter(directions),
+            Op::Clone => Op::Clone,
+            Op::Eq(k) => Op::Eq(f(k)),
+            Op::Extend(entries) => {
+                Op::Extend(entries.into_iter().map(|(k, v)| (f(k), v)).collect())
+            }
+            Op::Clear => Op::Clear,
+        }
+    }
+}
+
+// take items from front (true) or back (false) of both iterators, then the rest
+pub fn assert_same_ends<T, A, B>(mut a: A, mut b: B, directions: &[bool])
+where
+    T: PartialEq + core::fmt::Debug,
+    A: DoubleEndedIterator<Item = T>,
+    B: DoubleEndedIterator<Item = T>,
+{
+    for &front in directions {
+        if front {
+            assert_eq!(a.next(), b.next());
+        } else {
+            assert_eq!(a.next_back(), b.next_back());
+        }
+    }
+    assert_eq!(a.size_hint(), b.size_hint());
+    assert_eq!(a.collect::<Vec<_>>(), b.collect::<Vec<_>>());
+}
+
+/// apply `op` to both `tree` and `model`, asserting they return and contain
+/// the same
+pub fn apply(tree: &mut RBTree<Vec<u8>, u16>, model: &mut BTreeMap<Vec<u8>, u16>, op: Op<Vec<u8>>) {
+    match op {
+        Op::Insert(k, v) => assert_eq!(tree.insert(k.clone(), v), model.insert(k, v)),
+        Op::Remove(k) => assert_eq!(tree.remove(&k), model.remove(&k)),
+        Op::Get(k) => {
+            assert_eq!(tree.get(&k), model.get(&k));
+            assert_eq!(tree.contains_key(&k), model.contains_key(&k));
+            if model.contains_key(&k) {
+                assert_eq!(tree[&k], model[&k]);
+            }
+        }
+        Op::GetMut(k, v) => {
+            match (tree.get_mut(&k), model.get_mut(&k)) {
+                (Some(a), Some(b)) => {
+                    *a = v;
+                    *b = v;
+                }
+                (None, None) => {}
+                (a, b) => panic!("get_mut mismatch {:?} {:?}", a, b),
+            };
+        }
+        Op::PopFirst => {
+            let expected = model.keys().next().cloned().map(|k| {
+                let v = model.remove(&k).unwrap();
+                (k, v)
+            });
+            assert_eq!(tree.pop_first(), expected);
+        }
+        Op::PopLast => {
+            let expected = model.keys().next_back().cloned().map(|k| {
+                let v = model.remove(&k).unwrap();
+                (k, v)
+            });
+            assert_eq!(tree.pop_last(), expected);
+        }
+        Op::FirstLastMut(v) => {
+            if let Some((_, value)) = tree.get_first_mut() {
+                *value = v;
+            }
+            if let Some(value) = model.values_mut().next() {
+                *value = v;
+            }
+            if let Some((_, value)) = tree.get_last_mut() {
+                *value = v.wrapping_add(1);
+            }
+            if let Some(value) = model.values_mut().next_back() {
+                *value = v.wrapping_add(1);
+            }
+        }
+        Op::FindLessEqual(k) => {
+            let (node, found) = tree.find_less_equal(&k);
+            match model.range(..=k.clone()).next_back() {
+                Some((mk, mv)) => {
+                    assert!(!node.is_null());
+                    assert_eq!((node.get_key(), node.get_value()), (mk, mv));
+                    assert_eq!(found, *mk == k);
+                }
+                None => {
+                    assert!(node.is_null());
+                    assert!(!found);
+                }
+            }
+        }
+        Op::PrefixIter(prefix) => {
+            let expected = model
+                .range(prefix.clone()..)
+                .take_while(|(k, _)| k.starts_with(&prefix))
+                .collect::<Vec<_>>();
+            assert_eq!(tree.prefix_iter(&prefix).collect::<Vec<_>>(), expected);
+        }
+        Op::LongestPrefixMatch(k) => {
+            let expected = (0..=k.len())
+                .rev()
+                .find_map(|len| model.get_key_value(&k[..len]));
+            assert_eq!(tree.longest_prefix_match(&k), expected);
+        }
+        Op::Iter(directions) => {
+            assert_same_ends(tree.iter(), model.iter(), &directions);
+            assert!(tree.keys().eq(model.keys()));
+            assert!(tree.values().eq(model.values()));
+        }
+        Op::IterMut(directions, v) => {
+            {
+                let mut a = tree.iter_mut();
+                let mut b = model.iter_mut();
+                for front in directions {
+                    let (x, y) = if front {
+                        (a.next(), b.next())
+                    } else {
+                        (a.next_back(), b.next_back())
+                    };
+                    match (x, y) {
+                        (Some((ka, va)), Some((kb, vb))) => {
+                            assert_eq!(ka, kb);
+                            *va = va.wrapping_add(v);
+                            *vb = vb.wrapping_add(v);
+                        }
+                        (None, None) => {}
+                        (x, y) => panic!("iter_mut mismatch {:?} {:?}", x, y),
+                    }
+                }
+            }
+            for (a, b) in tree.values_mut().zip(model.values_mut()) {
+                *a ^= v;
+                *b ^= v;
+            }
+        }
+        Op::IntoIter(directions) => {
+            assert_same_ends(
+                tree.clone().into_iter(),
+                model.clone().into_iter(),
+                &directions,
+            );
+        }
+        Op::Clone => {
+            let cloned = tree.clone();
+            tree.clear();
+            *tree = cloned;
+        }
+        Op::Eq(k) => {
+            let mut other = tree.clone();
+            assert_eq!(*tree, other);
+            if other.remove(&k).is_some() {
+                assert_ne!(*tree, other);
+            } else {
+                other.insert(k, 0);
+                assert_ne!(*tree, other);
+            }
+        }
+        Op::Extend(entries) => {
+            tree.extend(entries.clone());
+            model.extend(entries);
+        }
+        Op::Clear => {
+            tree.clear();
+            model.clear();
+        }
+    }
+
+    assert_eq!(tree.len(), model.len());
+    assert_eq!(tree.is_empty(), model.is_empty());
+    assert_eq!(tree.get_first(), model.iter().next());
+    assert_eq!(tree.get_last(), model.iter().next_back());
+}
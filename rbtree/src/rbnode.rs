@@ -1,7 +1,8 @@
-use std::cmp::Ord;
-use std::cmp::Ordering;
-use std::fmt::{self, Debug};
-use std::ptr;
+use alloc::boxed::Box;
+use core::cmp::Ord;
+use core::cmp::Ordering;
+use core::fmt::{self, Debug};
+use core::ptr;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Color {
@@ -244,6 +245,7 @@ impl<K: Ord, V> NodePtr<K, V> {
 impl<K: Ord + Clone, V: Clone> NodePtr<K, V> {
     pub unsafe fn deep_clone(self) -> NodePtr<K, V> {
         let mut node = NodePtr::new((*self.0).key.clone(), (*self.0).value.clone());
+        node.set_color(self.get_color());
         if !self.left().is_null() {
             node.set_left(self.left().deep_clone());
             node.left().set_parent(node);
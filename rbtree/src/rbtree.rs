@@ -1,10 +1,13 @@
-use std::cmp::Ord;
-use std::cmp::Ordering;
-use std::fmt::{self, Debug};
-use std::iter::{FromIterator, IntoIterator};
-use std::marker;
-use std::mem;
-use std::ops::Index;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::borrow::Borrow;
+use core::cmp::Ord;
+use core::cmp::Ordering;
+use core::fmt::{self, Debug};
+use core::iter::{FromIterator, IntoIterator};
+use core::marker;
+use core::mem;
+use core::ops::Index;
 
 use crate::rbnode::{Color, NodePtr};
 
@@ -21,6 +24,9 @@ impl<K: Ord, V> Drop for RBTree<K, V> {
 
 impl<K: Ord + Clone, V: Clone> Clone for RBTree<K, V> {
     fn clone(&self) -> RBTree<K, V> {
+        if self.root.is_null() {
+            return RBTree::new();
+        }
         unsafe {
             let mut new = RBTree::new();
             new.root = self.root.deep_clone();
@@ -46,6 +52,7 @@ impl<K: Ord, V> Default for RBTree<K, V> {
     }
 }
 
+#[cfg(feature = "std")]
 impl<K: Ord + Debug, V: Debug> RBTree<K, V> {
     fn tree_print(&self, node: NodePtr<K, V>, direction: i32) {
         if node.is_null() {
@@ -246,7 +253,7 @@ impl<K: Ord, V> Iterator for IntoIter<K, V> {
             return None;
         }
 
-        let next = self.head.next();
+        let next = self.head.right();
         let obj = unsafe { Box::from_raw(self.head.0) };
         let (k, v) = obj.pair();
         self.head = next;
@@ -269,7 +276,7 @@ impl<K: Ord, V> DoubleEndedIterator for IntoIter<K, V> {
             return None;
         }
 
-        let prev = self.tail.prev();
+        let prev = self.tail.left();
         let obj = unsafe { Box::from_raw(self.tail.0) };
         let (k, v) = obj.pair();
         self.tail = prev;
@@ -325,7 +332,7 @@ impl<'a, K: Ord + 'a, V: 'a> DoubleEndedIterator for Iter<'a, K, V> {
             return None;
         }
 
-        if self.tail == self.head {
+        if self.tail.is_null() {
             return None;
         }
 
@@ -383,7 +390,7 @@ impl<'a, K: Ord + 'a, V: 'a> DoubleEndedIterator for IterMut<'a, K, V> {
             return None;
         }
 
-        if self.tail == self.head {
+        if self.tail.is_null() {
             return None;
         }
 
@@ -394,23 +401,125 @@ impl<'a, K: Ord + 'a, V: 'a> DoubleEndedIterator for IterMut<'a, K, V> {
     }
 }
 
+pub struct PrefixIter<'a, K: Ord + 'a, V: 'a> {
+    next: NodePtr<K, V>,
+    prefix: Vec<u8>,
+    _marker: marker::PhantomData<&'a ()>,
+}
+
+impl<'a, K: Ord + AsRef<[u8]> + 'a, V: 'a> Iterator for PrefixIter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        if self.next.is_null() {
+            return None;
+        }
+
+        let (k, v) = unsafe { (&(*self.next.0).key, &(*self.next.0).value) };
+        if !k.as_ref().starts_with(&self.prefix) {
+            self.next = NodePtr::null();
+            return None;
+        }
+        self.next = self.next.next();
+        Some((k, v))
+    }
+}
+
+/// SeekIter works like a storage engine iterator, it's invalid until
+/// positioned by one of the seek methods, next and prev move from the current
+/// entry and turn it invalid once they step over either end.
+pub struct SeekIter<'a, K: Ord + 'a, V: 'a> {
+    tree: &'a RBTree<K, V>,
+    current: NodePtr<K, V>,
+}
+
+impl<'a, K: Ord + 'a, V: 'a> Clone for SeekIter<'a, K, V> {
+    fn clone(&self) -> SeekIter<'a, K, V> {
+        SeekIter {
+            tree: self.tree,
+            current: self.current,
+        }
+    }
+}
+
+impl<'a, K: Ord + 'a, V: 'a> SeekIter<'a, K, V> {
+    pub fn valid(&self) -> bool {
+        !self.current.is_null()
+    }
+
+    pub fn key(&self) -> Option<&'a K> {
+        self.entry().map(|(k, _)| k)
+    }
+
+    pub fn value(&self) -> Option<&'a V> {
+        self.entry().map(|(_, v)| v)
+    }
+
+    pub fn entry(&self) -> Option<(&'a K, &'a V)> {
+        if self.current.is_null() {
+            return None;
+        }
+        unsafe { Some((&(*self.current.0).key, &(*self.current.0).value)) }
+    }
+
+    pub fn seek_to_first(&mut self) {
+        self.current = self.tree.first_child();
+    }
+
+    pub fn seek_to_last(&mut self) {
+        self.current = self.tree.last_child();
+    }
+
+    /// move to the first entry whose key is greater than or equal to `k`
+    pub fn seek<Q>(&mut self, k: &Q)
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let (node, found) = self.tree.find_less_equal_by(|key| k.cmp(key.borrow()));
+        self.current = if found {
+            node
+        } else if node.is_null() {
+            self.tree.first_child()
+        } else {
+            node.next()
+        };
+    }
+
+    /// move to the last entry whose key is less than or equal to `k`
+    pub fn seek_for_prev<Q>(&mut self, k: &Q)
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.current = self.tree.find_less_equal_by(|key| k.cmp(key.borrow())).0;
+    }
+
+    pub fn next(&mut self) {
+        if !self.current.is_null() {
+            self.current = self.current.next();
+        }
+    }
+
+    pub fn prev(&mut self) {
+        if !self.current.is_null() {
+            self.current = self.current.prev();
+        }
+    }
+}
+
 impl<K: Ord, V> IntoIterator for RBTree<K, V> {
     type Item = (K, V);
     type IntoIter = IntoIter<K, V>;
 
     fn into_iter(mut self) -> IntoIter<K, V> {
-        let iter = if self.root.is_null() {
-            IntoIter {
-                head: NodePtr::null(),
-                tail: NodePtr::null(),
-                len: self.len,
-            }
-        } else {
-            IntoIter {
-                head: self.first_child(),
-                tail: self.last_child(),
-                len: self.len,
-            }
+        // nodes are freed while iterating, relink them as a list through
+        // left and right first, so walking never touches a freed parent
+        let (head, tail) = unsafe { Self::link_in_order(self.root) };
+        let iter = IntoIter {
+            head,
+            tail,
+            len: self.len,
         };
         self.fast_clear();
         iter
@@ -425,6 +534,51 @@ impl<K: Ord, V> RBTree<K, V> {
         }
     }
 
+    /// entries must be sorted by key in strictly ascending order, the tree is
+    /// built balanced in O(n) without any rotation
+    #[cfg_attr(not(feature = "std"), allow(dead_code))]
+    pub(crate) fn from_sorted_vec(entries: Vec<(K, V)>) -> RBTree<K, V> {
+        let len = entries.len();
+        let mut red_depth = 0;
+        while (2 << red_depth) <= len {
+            red_depth += 1;
+        }
+
+        let mut iter = entries.into_iter();
+        let mut root = Self::build_sorted(&mut iter, len, 0, red_depth);
+        root.set_black_color();
+        RBTree { root, len }
+    }
+
+    fn build_sorted<I: Iterator<Item = (K, V)>>(
+        iter: &mut I,
+        count: usize,
+        depth: usize,
+        red_depth: usize,
+    ) -> NodePtr<K, V> {
+        if count == 0 {
+            return NodePtr::null();
+        }
+
+        let left_count = (count - 1) / 2;
+        let mut left = Self::build_sorted(iter, left_count, depth + 1, red_depth);
+        let (k, v) = iter.next().expect("sorted entries is less than count");
+        let mut node = NodePtr::new(k, v);
+        let mut right = Self::build_sorted(iter, count - 1 - left_count, depth + 1, red_depth);
+        if !left.is_null() {
+            node.set_left(left);
+            left.set_parent(node);
+        }
+        if !right.is_null() {
+            node.set_right(right);
+            right.set_parent(node);
+        }
+        if depth == red_depth {
+            node.set_red_color();
+        }
+        node
+    }
+
     pub fn len(&self) -> usize {
         self.len
     }
@@ -434,7 +588,6 @@ impl<K: Ord, V> RBTree<K, V> {
     }
 
     unsafe fn left_rotate(&mut self, mut node: NodePtr<K, V>) {
-        println!("---> rbtree left rotate");
         let mut right = node.right();
         let mut rleft = right.left();
         node.set_right(rleft);
@@ -455,7 +608,6 @@ impl<K: Ord, V> RBTree<K, V> {
     }
 
     unsafe fn right_rotate(&mut self, mut node: NodePtr<K, V>) {
-        println!("---> rbtree right rotate");
         let mut left = node.left();
         let mut lright = left.right();
         node.set_left(lright);
@@ -573,6 +725,14 @@ impl<K: Ord, V> RBTree<K, V> {
     }
 
     pub fn find_less_equal(&self, k: &K) -> (NodePtr<K, V>, bool) {
+        self.find_less_equal_by(|key| k.cmp(key))
+    }
+
+    // cmp returns the ordering of the searched target against the node key
+    fn find_less_equal_by<F>(&self, cmp: F) -> (NodePtr<K, V>, bool)
+    where
+        F: Fn(&K) -> Ordering,
+    {
         let mut less = NodePtr::null();
         let mut current = self.root;
         unsafe {
@@ -580,7 +740,7 @@ impl<K: Ord, V> RBTree<K, V> {
                 if current.is_null() {
                     break;
                 }
-                let next = match k.cmp(&(*current.0).key) {
+                let next = match cmp(&(*current.0).key) {
                     Ordering::Less => (*current.0).left,
                     Ordering::Greater => {
                         less = current;
@@ -705,9 +865,35 @@ impl<K: Ord, V> RBTree<K, V> {
     pub fn clear(&mut self) {
         let root = self.root;
         self.root = NodePtr::null();
+        self.len = 0;
         self.clear_recurse(root);
     }
 
+    unsafe fn link_in_order(mut node: NodePtr<K, V>) -> (NodePtr<K, V>, NodePtr<K, V>) {
+        if node.is_null() {
+            return (NodePtr::null(), NodePtr::null());
+        }
+
+        let (left_head, mut left_tail) = Self::link_in_order(node.left());
+        let (mut right_head, right_tail) = Self::link_in_order(node.right());
+        node.set_left(left_tail);
+        if !left_tail.is_null() {
+            left_tail.set_right(node);
+        }
+        node.set_right(right_head);
+        if !right_head.is_null() {
+            right_head.set_left(node);
+        }
+
+        let head = if left_head.is_null() { node } else { left_head };
+        let tail = if right_tail.is_null() {
+            node
+        } else {
+            right_tail
+        };
+        (head, tail)
+    }
+
     fn fast_clear(&mut self) {
         self.root = NodePtr::null();
     }
@@ -861,6 +1047,13 @@ impl<K: Ord, V> RBTree<K, V> {
         }
     }
 
+    pub fn seek_iter(&self) -> SeekIter<'_, K, V> {
+        SeekIter {
+            tree: self,
+            current: NodePtr::null(),
+        }
+    }
+
     pub fn iter_mut(&mut self) -> IterMut<K, V> {
         IterMut {
             head: self.first_child(),
@@ -871,9 +1064,54 @@ impl<K: Ord, V> RBTree<K, V> {
     }
 }
 
+/// prefix queries assume keys are ordered the same as their bytes, which
+/// holds for String, Vec<u8> and &str
+impl<K: Ord + AsRef<[u8]>, V> RBTree<K, V> {
+    pub fn prefix_iter<P: AsRef<[u8]> + ?Sized>(&self, prefix: &P) -> PrefixIter<'_, K, V> {
+        let prefix = prefix.as_ref();
+        let (node, found) = self.find_less_equal_by(|key| prefix.cmp(key.as_ref()));
+        let next = if found {
+            node
+        } else if node.is_null() {
+            self.first_child()
+        } else {
+            node.next()
+        };
+        PrefixIter {
+            next,
+            prefix: prefix.to_vec(),
+            _marker: marker::PhantomData,
+        }
+    }
+
+    /// return the longest key which is a prefix of `key`
+    pub fn longest_prefix_match<P: AsRef<[u8]> + ?Sized>(&self, key: &P) -> Option<(&K, &V)> {
+        let mut target = key.as_ref();
+        loop {
+            let (node, found) = self.find_less_equal_by(|key| target.cmp(key.as_ref()));
+            if node.is_null() {
+                return None;
+            }
+            let (k, v) = unsafe { (&(*node.0).key, &(*node.0).value) };
+            if found {
+                return Some((k, v));
+            }
+            // any key which is a prefix of target and less than target is
+            // between it and target, so it must be a prefix of their common part
+            let common = target
+                .iter()
+                .zip(k.as_ref())
+                .take_while(|(a, b)| a == b)
+                .count();
+            target = &target[..common];
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::RBTree;
+    use crate::rbnode::NodePtr;
     #[test]
     fn test_insert() {
         let mut m = RBTree::new();
@@ -903,6 +1141,17 @@ mod tests {
         assert_eq!(m2.len(), 2);
     }
 
+    #[test]
+    fn test_clone_then_remove() {
+        let m: RBTree<_, _> = (0..100).map(|i| (i, i)).collect();
+        let mut m2 = m.clone();
+        for i in 0..100 {
+            assert_eq!(m2.remove(&i), Some(i));
+        }
+        assert!(m2.is_empty());
+        assert_eq!(m.len(), 100);
+    }
+
     #[test]
     fn test_empty_remove() {
         let mut m: RBTree<isize, bool> = RBTree::new();
@@ -1013,6 +1262,158 @@ mod tests {
         assert_eq!(found, true);
     }
 
+    fn black_height(node: NodePtr<i32, i32>) -> usize {
+        if node.is_null() {
+            return 1;
+        }
+        if node.is_red_color() {
+            assert!(node.left().is_black_color() && node.right().is_black_color());
+        }
+        let left = black_height(node.left());
+        assert_eq!(left, black_height(node.right()));
+        if node.is_black_color() {
+            left + 1
+        } else {
+            left
+        }
+    }
+
+    #[test]
+    fn test_from_sorted_vec() {
+        for n in 0..70 {
+            let mut m = RBTree::from_sorted_vec((0..n).map(|i| (i, i * 2)).collect());
+            assert_eq!(m.len(), n as usize);
+            assert!(m.root.is_black_color());
+            black_height(m.root);
+            assert!(m.iter().map(|(k, _)| *k).eq(0..n));
+
+            for i in 0..n {
+                assert_eq!(m.remove(&i), Some(i * 2));
+                black_height(m.root);
+            }
+            assert!(m.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_prefix_iter() {
+        let m: RBTree<String, usize> = ["a", "ab", "abc", "abd", "ac", "b", "ba"]
+            .iter()
+            .enumerate()
+            .map(|(i, k)| (k.to_string(), i))
+            .collect();
+        let keys = |prefix: &str| {
+            m.prefix_iter(prefix)
+                .map(|(k, _)| k.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(keys("a"), vec!["a", "ab", "abc", "abd", "ac"]);
+        assert_eq!(keys("ab"), vec!["ab", "abc", "abd"]);
+        assert_eq!(keys("abc"), vec!["abc"]);
+        assert_eq!(keys("aa"), Vec::<&str>::new());
+        assert_eq!(keys("b"), vec!["b", "ba"]);
+        assert_eq!(keys("c"), Vec::<&str>::new());
+        assert_eq!(keys("").len(), m.len());
+        assert_eq!(m.prefix_iter("ab").next(), Some((&"ab".to_string(), &1)));
+
+        let empty: RBTree<Vec<u8>, ()> = RBTree::new();
+        assert!(empty.prefix_iter(&[1u8]).next().is_none());
+    }
+
+    #[test]
+    fn test_longest_prefix_match() {
+        let mut m = RBTree::new();
+        m.insert(b"/".to_vec(), 0);
+        m.insert(b"/api".to_vec(), 1);
+        m.insert(b"/api/v1".to_vec(), 2);
+        m.insert(b"/api/v1/users".to_vec(), 3);
+        m.insert(b"/apz".to_vec(), 4);
+        m.insert(b"/static".to_vec(), 5);
+
+        let matched = |key: &str| m.longest_prefix_match(key).map(|(_, v)| *v);
+        assert_eq!(matched("/api/v1/users"), Some(3));
+        assert_eq!(matched("/api/v1/users/1"), Some(3));
+        assert_eq!(matched("/api/v1/user"), Some(2));
+        assert_eq!(matched("/api/v2"), Some(1));
+        assert_eq!(matched("/apy"), Some(0));
+        assert_eq!(matched("/b"), Some(0));
+        assert_eq!(matched("/zzz"), Some(0));
+        assert_eq!(matched("api"), None);
+        assert_eq!(matched(""), None);
+
+        m.insert(Vec::new(), 6);
+        assert_eq!(m.longest_prefix_match("api"), Some((&Vec::new(), &6)));
+    }
+
+    #[test]
+    fn test_seek_iter() {
+        let m: RBTree<_, _> = (0..10).map(|i| (i * 10, i)).collect();
+        let mut iter = m.seek_iter();
+        assert!(!iter.valid());
+        assert_eq!(iter.entry(), None);
+
+        iter.seek(&35);
+        assert_eq!(iter.entry(), Some((&40, &4)));
+        iter.next();
+        assert_eq!(iter.key(), Some(&50));
+        iter.prev();
+        iter.prev();
+        assert_eq!(iter.key(), Some(&30));
+
+        iter.seek(&40);
+        assert_eq!(iter.key(), Some(&40));
+        iter.seek(&-1);
+        assert_eq!(iter.key(), Some(&0));
+        iter.prev();
+        assert!(!iter.valid());
+        iter.next();
+        assert!(!iter.valid());
+        iter.seek(&91);
+        assert!(!iter.valid());
+
+        iter.seek_for_prev(&35);
+        assert_eq!(iter.value(), Some(&3));
+        iter.seek_for_prev(&30);
+        assert_eq!(iter.value(), Some(&3));
+        iter.seek_for_prev(&1000);
+        assert_eq!(iter.key(), Some(&90));
+        iter.next();
+        assert!(!iter.valid());
+        iter.seek_for_prev(&-1);
+        assert!(!iter.valid());
+
+        iter.seek_to_last();
+        let mut keys = vec![];
+        while let Some(k) = iter.key() {
+            keys.push(*k);
+            iter.prev();
+        }
+        assert_eq!(keys, (0..10).rev().map(|i| i * 10).collect::<Vec<_>>());
+        iter.seek_to_first();
+        assert_eq!(iter.key(), Some(&0));
+
+        let empty: RBTree<i32, i32> = RBTree::new();
+        let mut iter = empty.seek_iter();
+        iter.seek_to_first();
+        assert!(!iter.valid());
+        iter.seek(&1);
+        assert!(!iter.valid());
+    }
+
+    #[test]
+    fn test_seek_iter_borrowed_key() {
+        let m: RBTree<String, usize> = ["apple", "banana", "cherry"]
+            .iter()
+            .enumerate()
+            .map(|(i, k)| (k.to_string(), i))
+            .collect();
+        let mut iter = m.seek_iter();
+        iter.seek("b");
+        assert_eq!(iter.value(), Some(&1));
+        iter.seek_for_prev("b");
+        assert_eq!(iter.key().map(String::as_str), Some("apple"));
+    }
+
     #[test]
     fn test_remove() {
         let mut m = RBTree::new();
@@ -1255,6 +1656,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "std")]
     fn test_tree_print() {
         let mut a = RBTree::new();
         a.insert("a", 1);
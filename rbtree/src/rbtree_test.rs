@@ -1,5 +1,7 @@
+use crate::model::{apply, Op};
 use crate::rbtree::RBTree;
 use proptest::prelude::*;
+use std::collections::BTreeMap;
 
 proptest! {
     #![proptest_config(ProptestConfig::with_cases(10))]
@@ -37,3 +39,98 @@ proptest! {
         assert_eq!(tree.len(), back.len());
     }
 }
+
+fn key_strategy() -> impl Strategy<Value = Vec<u8>> {
+    // small alphabet and length make keys collide and share prefixes
+    prop::collection::vec(0u8..4, 0..4)
+}
+
+fn op_strategy() -> impl Strategy<Value = Op<Vec<u8>>> {
+    let directions = prop::collection::vec(any::<bool>(), 0..70);
+    prop_oneof![
+        10 => (key_strategy(), any::<u16>()).prop_map(|(k, v)| Op::Insert(k, v)),
+        5 => key_strategy().prop_map(Op::Remove),
+        2 => key_strategy().prop_map(Op::Get),
+        2 => (key_strategy(), any::<u16>()).prop_map(|(k, v)| Op::GetMut(k, v)),
+        1 => Just(Op::PopFirst),
+        1 => Just(Op::PopLast),
+        1 => any::<u16>().prop_map(Op::FirstLastMut),
+        2 => key_strategy().prop_map(Op::FindLessEqual),
+        1 => key_strategy().prop_map(Op::PrefixIter),
+        1 => key_strategy().prop_map(Op::LongestPrefixMatch),
+        2 => directions.clone().prop_map(Op::Iter),
+        1 => (directions.clone(), any::<u16>()).prop_map(|(d, v)| Op::IterMut(d, v)),
+        1 => directions.prop_map(Op::IntoIter),
+        1 => Just(Op::Clone),
+        1 => key_strategy().prop_map(Op::Eq),
+        1 => prop::collection::vec((key_strategy(), any::<u16>()), 0..10).prop_map(Op::Extend),
+        1 => Just(Op::Clear),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn test_model_based(ops in prop::collection::vec(op_strategy(), 0..200)) {
+        let mut tree = RBTree::new();
+        let mut model = BTreeMap::new();
+        for op in ops {
+            apply(&mut tree, &mut model, op);
+        }
+        assert!(tree.iter().eq(model.iter()));
+    }
+}
+
+// regressions found by test_model_based
+
+#[test]
+fn test_clone_empty() {
+    let tree = RBTree::<u32, u32>::new();
+    let cloned = tree.clone();
+    assert!(cloned.is_empty());
+    assert_eq!(cloned.iter().next(), None);
+}
+
+#[test]
+fn test_clear_resets_len() {
+    let mut tree: RBTree<_, _> = (0..10).map(|i| (i, i)).collect();
+    tree.clear();
+    assert_eq!(tree.len(), 0);
+    assert!(tree.is_empty());
+    tree.insert(1, 1);
+    assert_eq!(tree.len(), 1);
+}
+
+#[test]
+fn test_next_back_reaches_first() {
+    let mut tree: RBTree<_, _> = (0..5).map(|i| (i, i)).collect();
+    let keys: Vec<_> = tree.iter().rev().map(|(k, _)| *k).collect();
+    assert_eq!(keys, vec![4, 3, 2, 1, 0]);
+    let mut iter = tree.iter();
+    assert_eq!(iter.next(), Some((&0, &0)));
+    assert_eq!(iter.next_back(), Some((&4, &4)));
+    assert_eq!(iter.next_back(), Some((&3, &3)));
+    assert_eq!(iter.next(), Some((&1, &1)));
+    assert_eq!(iter.next_back(), Some((&2, &2)));
+    assert_eq!(iter.next_back(), None);
+    assert_eq!(iter.next(), None);
+
+    let keys: Vec<_> = tree.iter_mut().rev().map(|(k, _)| *k).collect();
+    assert_eq!(keys, vec![4, 3, 2, 1, 0]);
+}
+
+#[test]
+fn test_into_iter_frees_in_order() {
+    // `IntoIter` used to walk parent links of nodes it had already freed
+    let tree: RBTree<_, _> = (0..100).map(|i| (i, i.to_string())).collect();
+    let keys: Vec<_> = tree.clone().into_iter().map(|(k, _)| k).collect();
+    assert_eq!(keys, (0..100).collect::<Vec<_>>());
+    let keys: Vec<_> = tree.clone().into_iter().rev().map(|(k, _)| k).collect();
+    assert_eq!(keys, (0..100).rev().collect::<Vec<_>>());
+
+    let mut iter = tree.into_iter();
+    assert_eq!(iter.next(), Some((0, "0".to_string())));
+    assert_eq!(iter.next_back(), Some((99, "99".to_string())));
+    assert_eq!(iter.size_hint(), (98, Some(98)));
+    // dropping frees the rest
+    drop(iter);
+}
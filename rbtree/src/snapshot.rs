@@ -0,0 +1,395 @@
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use crate::rbtree::RBTree;
+
+const MAGIC: &[u8; 4] = b"RBTS";
+const VERSION: u16 = 1;
+// magic(4) + version(2) + reserved(2) + count(8) + payload length(8) + checksum(4)
+const HEADER_LEN: usize = 28;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u16),
+    Truncated,
+    ChecksumMismatch { expected: u32, actual: u32 },
+    Corrupted(String),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "snapshot io error: {}", e),
+            SnapshotError::BadMagic => write!(f, "not a rbtree snapshot"),
+            SnapshotError::UnsupportedVersion(v) => {
+                write!(f, "unsupported snapshot version {}", v)
+            }
+            SnapshotError::Truncated => write!(f, "snapshot is truncated"),
+            SnapshotError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "snapshot checksum mismatch, expect {:#010x} but get {:#010x}",
+                expected, actual
+            ),
+            SnapshotError::Corrupted(reason) => write!(f, "snapshot is corrupted: {}", reason),
+        }
+    }
+}
+
+impl Error for SnapshotError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SnapshotError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> Self {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            SnapshotError::Truncated
+        } else {
+            SnapshotError::Io(e)
+        }
+    }
+}
+
+/// Codec converts key or value to bytes and back, encode output is framed by
+/// snapshot, so decode always get exactly the bytes produced by encode
+pub trait Codec<T> {
+    fn encode(&self, value: &T, buf: &mut Vec<u8>);
+    fn decode(&self, buf: &[u8]) -> Result<T, SnapshotError>;
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+pub struct BytesCodec;
+
+impl Codec<Vec<u8>> for BytesCodec {
+    fn encode(&self, value: &Vec<u8>, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(value);
+    }
+
+    fn decode(&self, buf: &[u8]) -> Result<Vec<u8>, SnapshotError> {
+        Ok(buf.to_vec())
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+pub struct StringCodec;
+
+impl Codec<String> for StringCodec {
+    fn encode(&self, value: &String, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(value.as_bytes());
+    }
+
+    fn decode(&self, buf: &[u8]) -> Result<String, SnapshotError> {
+        String::from_utf8(buf.to_vec())
+            .map_err(|e| SnapshotError::Corrupted(format!("invalid utf8 string: {}", e)))
+    }
+}
+
+/// IntCodec encodes integers as fixed width little endian bytes
+#[derive(Debug, Default, Copy, Clone)]
+pub struct IntCodec;
+
+macro_rules! impl_int_codec {
+    ($($t:ty),*) => {
+        $(
+            impl Codec<$t> for IntCodec {
+                fn encode(&self, value: &$t, buf: &mut Vec<u8>) {
+                    buf.extend_from_slice(&value.to_le_bytes());
+                }
+
+                fn decode(&self, buf: &[u8]) -> Result<$t, SnapshotError> {
+                    let mut bytes = [0; std::mem::size_of::<$t>()];
+                    if buf.len() != bytes.len() {
+                        return Err(SnapshotError::Corrupted(format!(
+                            "{} should be {} bytes but get {}",
+                            stringify!($t),
+                            bytes.len(),
+                            buf.len()
+                        )));
+                    }
+                    bytes.copy_from_slice(buf);
+                    Ok(<$t>::from_le_bytes(bytes))
+                }
+            }
+        )*
+    };
+}
+
+impl_int_codec!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
+
+/// Snapshot saves a tree as
+///
+/// | magic "RBTS" | version u16 | reserved u16 | count u64 | payload len u64 | crc32 u32 |
+///
+/// followed by payload which is `count` entries in ascending key order, each
+/// entry is `key len u32 | key | value len u32 | value`. All integers are
+/// little endian, the checksum covers the whole payload.
+pub struct Snapshot<KC, VC> {
+    key_codec: KC,
+    value_codec: VC,
+}
+
+impl<KC, VC> Snapshot<KC, VC> {
+    pub fn new(key_codec: KC, value_codec: VC) -> Self {
+        Snapshot {
+            key_codec,
+            value_codec,
+        }
+    }
+
+    pub fn write<K, V, W>(&self, tree: &RBTree<K, V>, mut writer: W) -> Result<(), SnapshotError>
+    where
+        K: Ord,
+        KC: Codec<K>,
+        VC: Codec<V>,
+        W: Write,
+    {
+        let mut payload = Vec::new();
+        let mut field = Vec::new();
+        for (k, v) in tree.iter() {
+            field.clear();
+            self.key_codec.encode(k, &mut field);
+            write_field(&mut payload, &field)?;
+            field.clear();
+            self.value_codec.encode(v, &mut field);
+            write_field(&mut payload, &field)?;
+        }
+
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&VERSION.to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(&(tree.len() as u64).to_le_bytes());
+        header.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+        header.extend_from_slice(&crc32(&payload).to_le_bytes());
+        writer.write_all(&header)?;
+        writer.write_all(&payload)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn read<K, V, R>(&self, mut reader: R) -> Result<RBTree<K, V>, SnapshotError>
+    where
+        K: Ord,
+        KC: Codec<K>,
+        VC: Codec<V>,
+        R: Read,
+    {
+        let mut header = [0; HEADER_LEN];
+        reader.read_exact(&mut header)?;
+        if &header[0..4] != MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        let version = u16::from_le_bytes([header[4], header[5]]);
+        if version != VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        let count = read_u64(&header[8..16]);
+        let payload_len = read_u64(&header[16..24]);
+        let expected = u32::from_le_bytes([header[24], header[25], header[26], header[27]]);
+
+        // never trust the length in header to allocate memory
+        let mut payload = Vec::new();
+        reader.take(payload_len).read_to_end(&mut payload)?;
+        if (payload.len() as u64) < payload_len {
+            return Err(SnapshotError::Truncated);
+        }
+        let actual = crc32(&payload);
+        if actual != expected {
+            return Err(SnapshotError::ChecksumMismatch { expected, actual });
+        }
+
+        let mut entries: Vec<(K, V)> = Vec::new();
+        let mut buf = payload.as_slice();
+        for _ in 0..count {
+            let k = self.key_codec.decode(read_field(&mut buf)?)?;
+            let v = self.value_codec.decode(read_field(&mut buf)?)?;
+            if let Some((last, _)) = entries.last() {
+                if *last >= k {
+                    return Err(SnapshotError::Corrupted(
+                        "keys aren't in ascending order".to_string(),
+                    ));
+                }
+            }
+            entries.push((k, v));
+        }
+        if !buf.is_empty() {
+            return Err(SnapshotError::Corrupted(format!(
+                "{} bytes left after {} entries",
+                buf.len(),
+                count
+            )));
+        }
+
+        Ok(RBTree::from_sorted_vec(entries))
+    }
+}
+
+fn write_field(payload: &mut Vec<u8>, field: &[u8]) -> Result<(), SnapshotError> {
+    if field.len() > u32::MAX as usize {
+        return Err(SnapshotError::Io(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "encoded key or value exceeds 4GiB",
+        )));
+    }
+    payload.extend_from_slice(&(field.len() as u32).to_le_bytes());
+    payload.extend_from_slice(field);
+    Ok(())
+}
+
+fn read_field<'a>(buf: &mut &'a [u8]) -> Result<&'a [u8], SnapshotError> {
+    if buf.len() < 4 {
+        return Err(SnapshotError::Corrupted(
+            "entry count is larger than entries in payload".to_string(),
+        ));
+    }
+    let len = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+    if buf.len() - 4 < len {
+        return Err(SnapshotError::Corrupted(format!(
+            "field length {} exceeds payload",
+            len
+        )));
+    }
+    let field = &buf[4..4 + len];
+    *buf = &buf[4 + len..];
+    Ok(field)
+}
+
+fn read_u64(buf: &[u8]) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(buf);
+    u64::from_le_bytes(bytes)
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &b in data {
+        crc ^= u32::from(b);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{crc32, BytesCodec, IntCodec, Snapshot, SnapshotError, StringCodec, HEADER_LEN};
+    use crate::rbtree::RBTree;
+
+    fn int_tree(n: u64) -> RBTree<u64, String> {
+        (0..n).map(|i| (i * 3, format!("value-{}", i))).collect()
+    }
+
+    fn save(tree: &RBTree<u64, String>) -> Vec<u8> {
+        let mut buf = Vec::new();
+        Snapshot::new(IntCodec, StringCodec)
+            .write(tree, &mut buf)
+            .unwrap();
+        buf
+    }
+
+    fn load(buf: &[u8]) -> Result<RBTree<u64, String>, SnapshotError> {
+        Snapshot::new(IntCodec, StringCodec).read(buf)
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for n in &[0, 1, 2, 3, 7, 8, 100, 1000] {
+            let tree = int_tree(*n);
+            let mut loaded = load(&save(&tree)).unwrap();
+            assert_eq!(loaded.len(), tree.len());
+            assert!(loaded.iter().eq(tree.iter()));
+
+            // loaded tree should still be a valid rbtree
+            for i in 0..*n {
+                assert_eq!(loaded.remove(&(i * 3)), Some(format!("value-{}", i)));
+                loaded.insert(i * 3 + 1, String::new());
+            }
+            assert_eq!(loaded.len(), *n as usize);
+        }
+    }
+
+    #[test]
+    fn test_bytes_key() {
+        let tree: RBTree<Vec<u8>, u32> = (0..50u32).map(|i| (vec![i as u8; 3], i)).collect();
+        let snapshot = Snapshot::new(BytesCodec, IntCodec);
+        let mut buf = Vec::new();
+        snapshot.write(&tree, &mut buf).unwrap();
+        let loaded: RBTree<Vec<u8>, u32> = snapshot.read(buf.as_slice()).unwrap();
+        assert_eq!(loaded, tree);
+    }
+
+    #[test]
+    fn test_truncated() {
+        let buf = save(&int_tree(10));
+        for len in &[0, 3, HEADER_LEN - 1, HEADER_LEN, buf.len() - 1] {
+            match load(&buf[..*len]) {
+                Err(SnapshotError::Truncated) => {}
+                r => panic!("expect truncated error but get {:?}", r.map(|t| t.len())),
+            }
+        }
+    }
+
+    #[test]
+    fn test_corrupted() {
+        let buf = save(&int_tree(10));
+
+        let mut bad = buf.clone();
+        bad[0] = b'X';
+        assert!(matches!(load(&bad), Err(SnapshotError::BadMagic)));
+
+        let mut bad = buf.clone();
+        bad[4] = 9;
+        assert!(matches!(
+            load(&bad),
+            Err(SnapshotError::UnsupportedVersion(9))
+        ));
+
+        let mut bad = buf.clone();
+        let last = bad.len() - 1;
+        bad[last] ^= 0xFF;
+        assert!(matches!(
+            load(&bad),
+            Err(SnapshotError::ChecksumMismatch { .. })
+        ));
+
+        let mut bad = buf.clone();
+        bad[8] = 11;
+        assert!(matches!(load(&bad), Err(SnapshotError::Corrupted(_))));
+
+        let mut bad = buf;
+        bad[8] = 9;
+        assert!(matches!(load(&bad), Err(SnapshotError::Corrupted(_))));
+    }
+
+    #[test]
+    fn test_unsorted_entries() {
+        let mut tree = RBTree::new();
+        tree.insert(1u64, String::from("a"));
+        tree.insert(2u64, String::from("b"));
+        let mut buf = save(&tree);
+        // swap the two keys and fix checksum
+        buf[HEADER_LEN + 4] = 2;
+        buf[HEADER_LEN + 4 + 8 + 4 + 1 + 4] = 1;
+        let crc = crc32(&buf[HEADER_LEN..]);
+        buf[24..28].copy_from_slice(&crc.to_le_bytes());
+        assert!(matches!(load(&buf), Err(SnapshotError::Corrupted(_))));
+    }
+}
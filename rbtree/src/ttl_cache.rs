@@ -0,0 +1,308 @@
+use std::time::{Duration, Instant};
+
+use crate::rbtree::RBTree;
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub expirations: u64,
+}
+
+struct Entry<V> {
+    value: V,
+    expire_at: ExpireKey,
+}
+
+// entries which never expire sort after all the others, so they are evicted
+// last
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Expiry {
+    At(Instant),
+    Never,
+}
+
+impl Expiry {
+    fn is_reached(self, now: Instant) -> bool {
+        match self {
+            Expiry::At(at) => at <= now,
+            Expiry::Never => false,
+        }
+    }
+}
+
+// entries which expire at the same instant are told apart by insertion sequence
+type ExpireKey = (Expiry, u64);
+
+/// TtlCache keeps at most `capacity` entries, each entry is dropped once its ttl
+/// is reached, when the cache is full the entry which expires soonest is evicted.
+pub struct TtlCache<K: Ord + Clone, V> {
+    entries: RBTree<K, Entry<V>>,
+    expiry: RBTree<ExpireKey, K>,
+    capacity: usize,
+    seq: u64,
+    stats: CacheStats,
+}
+
+impl<K: Ord + Clone, V> TtlCache<K, V> {
+    pub fn new(capacity: usize) -> TtlCache<K, V> {
+        assert!(capacity > 0, "cache capacity should be larger than 0");
+        TtlCache {
+            entries: RBTree::new(),
+            expiry: RBTree::new(),
+            capacity,
+            seq: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    pub fn insert(&mut self, k: K, v: V, ttl: Duration) -> Option<V> {
+        self.insert_at(k, v, ttl, Instant::now())
+    }
+
+    /// a `ttl` too large to be added to `now`, e.g. `Duration::MAX`, never
+    /// expires
+    pub fn insert_at(&mut self, k: K, v: V, ttl: Duration, now: Instant) -> Option<V> {
+        let old = self.remove(&k);
+        if old.is_none() && self.entries.len() == self.capacity {
+            self.evict_one();
+        }
+
+        let expiry = now.checked_add(ttl).map_or(Expiry::Never, Expiry::At);
+        let expire_at = (expiry, self.seq);
+        self.seq += 1;
+        self.expiry.insert(expire_at, k.clone());
+        self.entries.insert(
+            k,
+            Entry {
+                value: v,
+                expire_at,
+            },
+        );
+        old
+    }
+
+    pub fn get(&mut self, k: &K) -> Option<&V> {
+        self.get_at(k, Instant::now())
+    }
+
+    pub fn get_at(&mut self, k: &K, now: Instant) -> Option<&V> {
+        let expire_at = match self.entries.get(k) {
+            Some(entry) => entry.expire_at,
+            None => {
+                self.stats.misses += 1;
+                return None;
+            }
+        };
+
+        if expire_at.0.is_reached(now) {
+            self.remove(k);
+            self.stats.expirations += 1;
+            self.stats.misses += 1;
+            return None;
+        }
+
+        self.stats.hits += 1;
+        self.entries.get(k).map(|entry| &entry.value)
+    }
+
+    pub fn contains_key(&self, k: &K) -> bool {
+        self.contains_key_at(k, Instant::now())
+    }
+
+    /// like `get_at`, an expired entry which isn't purged yet isn't contained
+    pub fn contains_key_at(&self, k: &K, now: Instant) -> bool {
+        self.entries
+            .get(k)
+            .is_some_and(|entry| !entry.expire_at.0.is_reached(now))
+    }
+
+    pub fn remove(&mut self, k: &K) -> Option<V> {
+        let entry = self.entries.remove(k)?;
+        self.expiry.remove(&entry.expire_at);
+        Some(entry.value)
+    }
+
+    pub fn purge_expired(&mut self, now: Instant) -> usize {
+        let mut purged = 0;
+        while let Some((expire_at, _)) = self.expiry.get_first() {
+            if !expire_at.0.is_reached(now) {
+                break;
+            }
+            let (_, k) = self.expiry.pop_first().unwrap();
+            self.entries.remove(&k);
+            purged += 1;
+        }
+        self.stats.expirations += purged as u64;
+        purged
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.expiry.clear();
+    }
+
+    fn evict_one(&mut self) {
+        if let Some((_, k)) = self.expiry.pop_first() {
+            self.entries.remove(&k);
+            self.stats.evictions += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CacheStats, TtlCache};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_insert_get() {
+        let mut cache = TtlCache::new(10);
+        let now = Instant::now();
+        assert_eq!(cache.insert_at(1, "a", Duration::from_secs(10), now), None);
+        assert_eq!(
+            cache.insert_at(1, "b", Duration::from_secs(10), now),
+            Some("a")
+        );
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.get_at(&1, now), Some(&"b"));
+        assert_eq!(cache.get_at(&2, now), None);
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 1,
+                misses: 1,
+                evictions: 0,
+                expirations: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_get_expired() {
+        let mut cache = TtlCache::new(10);
+        let now = Instant::now();
+        cache.insert_at(1, 1, Duration::from_secs(10), now);
+        assert_eq!(cache.get_at(&1, now + Duration::from_secs(5)), Some(&1));
+        assert_eq!(cache.get_at(&1, now + Duration::from_secs(10)), None);
+        assert!(cache.is_empty());
+        assert_eq!(cache.stats().expirations, 1);
+        assert_eq!(cache.stats().misses, 1);
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut cache = TtlCache::new(10);
+        cache.insert(1, 1, Duration::from_secs(10));
+        assert_eq!(cache.remove(&1), Some(1));
+        assert_eq!(cache.remove(&1), None);
+        assert!(cache.is_empty());
+        assert_eq!(
+            cache.purge_expired(Instant::now() + Duration::from_secs(20)),
+            0
+        );
+    }
+
+    #[test]
+    fn test_purge_expired() {
+        let mut cache = TtlCache::new(10);
+        let now = Instant::now();
+        for i in 0..10 {
+            cache.insert_at(i, i, Duration::from_secs(i + 1), now);
+        }
+        assert_eq!(cache.purge_expired(now + Duration::from_secs(5)), 5);
+        assert_eq!(cache.len(), 5);
+        for i in 0..5 {
+            assert!(!cache.contains_key(&i));
+        }
+        for i in 5..10 {
+            assert!(cache.contains_key(&i));
+        }
+        assert_eq!(cache.stats().expirations, 5);
+    }
+
+    #[test]
+    fn test_evict_soonest_expiring() {
+        let mut cache = TtlCache::new(3);
+        let now = Instant::now();
+        cache.insert_at(1, 1, Duration::from_secs(30), now);
+        cache.insert_at(2, 2, Duration::from_secs(10), now);
+        cache.insert_at(3, 3, Duration::from_secs(20), now);
+        cache.insert_at(4, 4, Duration::from_secs(40), now);
+        assert_eq!(cache.len(), 3);
+        assert!(!cache.contains_key(&2));
+        assert_eq!(cache.stats().evictions, 1);
+
+        //update existing key shouldn't evict
+        cache.insert_at(4, 5, Duration::from_secs(5), now);
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.stats().evictions, 1);
+
+        cache.insert_at(5, 5, Duration::from_secs(50), now);
+        assert!(!cache.contains_key(&4));
+        assert_eq!(cache.stats().evictions, 2);
+    }
+
+    #[test]
+    fn test_never_expire() {
+        let mut cache = TtlCache::new(2);
+        let now = Instant::now();
+        cache.insert_at(1, 1, Duration::MAX, now);
+        cache.insert_at(2, 2, Duration::from_secs(10), now);
+        assert_eq!(cache.purge_expired(now + Duration::from_secs(3600)), 1);
+        assert_eq!(cache.get_at(&1, now + Duration::from_secs(3600)), Some(&1));
+
+        // evicted after the entries which expire
+        cache.insert_at(3, 3, Duration::from_secs(10), now);
+        cache.insert_at(4, 4, Duration::from_secs(20), now);
+        assert!(cache.contains_key_at(&1, now));
+        assert!(!cache.contains_key_at(&3, now));
+        cache.insert_at(5, 5, Duration::MAX, now);
+        assert!(!cache.contains_key_at(&4, now));
+        cache.insert_at(6, 6, Duration::MAX, now);
+        assert!(!cache.contains_key_at(&1, now));
+        assert!(cache.contains_key_at(&5, now));
+        assert!(cache.contains_key_at(&6, now));
+    }
+
+    #[test]
+    fn test_contains_expired() {
+        let mut cache = TtlCache::new(10);
+        let now = Instant::now();
+        cache.insert_at(1, 1, Duration::from_secs(10), now);
+        assert!(cache.contains_key_at(&1, now + Duration::from_secs(5)));
+        assert!(!cache.contains_key_at(&1, now + Duration::from_secs(10)));
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.get_at(&1, now + Duration::from_secs(10)), None);
+    }
+
+    #[test]
+    fn test_same_expire_time() {
+        let mut cache = TtlCache::new(10);
+        let now = Instant::now();
+        for i in 0..5 {
+            cache.insert_at(i, i, Duration::from_secs(10), now);
+        }
+        assert_eq!(cache.len(), 5);
+        assert_eq!(cache.remove(&2), Some(2));
+        assert_eq!(cache.purge_expired(now + Duration::from_secs(10)), 4);
+        assert!(cache.is_empty());
+    }
+}